@@ -0,0 +1,151 @@
+// Handshake throughput: `cargo bench --bench handshake [FILTER]`.
+//
+// Each case runs for about a second and reports operations per second; the
+// loopback cases include the kernel, so compare them on the same machine.
+
+use std::{
+    env,
+    hint::black_box,
+    thread,
+    time::{Duration, Instant},
+};
+
+use weso::{
+    base64,
+    client::Client,
+    digest::Digest,
+    frame::apply_mask,
+    handshake::{accept_key, Limits, Request, Response},
+    message::Message,
+    server::{Config, ConnectionId, Handler, Server, ServerHandle},
+    sha1::Sha1Ctx,
+};
+
+const RUN: Duration = Duration::from_secs(1);
+
+const REQUEST: &[u8] = b"GET /chat HTTP/1.1\r\n\
+Host: server.example.com\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Origin: http://example.com\r\n\
+Sec-WebSocket-Protocol: chat, superchat\r\n\
+Sec-WebSocket-Version: 13\r\n\r\n";
+
+// Runs `op` in batches until RUN has passed; returns operations per second.
+fn measure<F: FnMut() -> usize>(mut op: F) -> f64 {
+    let start = Instant::now();
+    let mut count = 0;
+    while start.elapsed() < RUN {
+        count += op();
+    }
+    count as f64 / start.elapsed().as_secs_f64()
+}
+
+fn report(name: &str, rate: f64, unit: &str) {
+    println!("{name:<28} {rate:>14.0} {unit}/s");
+}
+
+#[derive(Clone)]
+struct Idle;
+
+impl Handler for Idle {
+    fn on_message(&mut self, _server: &ServerHandle, _id: ConnectionId, _message: Message) {}
+}
+
+fn loopback(threads: usize) -> f64 {
+    let config = Config {
+        handshake_rate: None,
+        max_pending_handshakes: 65536,
+        ..Config::default()
+    };
+    let server =
+        Server::with_config(std::net::TcpListener::bind("127.0.0.1:0").unwrap(), config).unwrap();
+    let url = format!("ws://{}/chat", server.local_addr().unwrap());
+    let handle = server.handle();
+    let running = thread::spawn(move || server.run(Idle));
+
+    let start = Instant::now();
+    let clients: Vec<_> = (0..threads)
+        .map(|_| {
+            let url = url.clone();
+            thread::spawn(move || {
+                let mut count = 0usize;
+                while start.elapsed() < RUN {
+                    let mut client = Client::connect(&url).unwrap();
+                    let _ = client.close(1000, "");
+                    count += 1;
+                }
+                count
+            })
+        })
+        .collect();
+    let total: usize = clients
+        .into_iter()
+        .map(|client| client.join().unwrap())
+        .sum();
+    let rate = total as f64 / start.elapsed().as_secs_f64();
+
+    handle.shutdown();
+    let _ = running.join();
+    rate
+}
+
+fn main() {
+    let filter = env::args().skip(1).find(|arg| !arg.starts_with("--"));
+    let enabled = |name: &str| filter.as_deref().is_none_or(|filter| name.contains(filter));
+
+    if enabled("sha1") {
+        let data = vec![0x5au8; 1 << 20];
+        let rate = measure(|| {
+            black_box(Sha1Ctx::digest(black_box(&data)));
+            data.len()
+        });
+        report("sha1 1 MiB", rate / (1 << 20) as f64, "MiB");
+    }
+    if enabled("base64") {
+        let data = vec![0xa5u8; 1 << 20];
+        let encoded = base64::encode(&data);
+        let rate = measure(|| black_box(base64::encode(black_box(&data))).len());
+        report("base64 encode", rate / (1 << 20) as f64, "MiB");
+        let rate = measure(|| black_box(base64::decode(black_box(&encoded)).unwrap()).len());
+        report("base64 decode", rate / (1 << 20) as f64, "MiB");
+    }
+    if enabled("mask") {
+        let mut data = vec![0xa5u8; 1 << 20];
+        let rate = measure(|| {
+            apply_mask(black_box(&mut data), [1, 2, 3, 4], 0);
+            data.len()
+        });
+        report("mask 1 MiB", rate / (1 << 20) as f64, "MiB");
+    }
+    if enabled("accept_key") {
+        let rate = measure(|| {
+            for _ in 0..1000 {
+                black_box(accept_key(black_box("dGhlIHNhbXBsZSBub25jZQ==")));
+            }
+            1000
+        });
+        report("accept_key", rate, "keys");
+    }
+    if enabled("parse") {
+        let limits = Limits::default();
+        let rate = measure(|| {
+            for _ in 0..1000 {
+                let (request, _) = Request::parse(black_box(REQUEST), &limits)
+                    .unwrap()
+                    .unwrap();
+                let response = Response::switching_protocols(request.key().unwrap());
+                black_box(response.to_bytes());
+            }
+            1000
+        });
+        report("parse and respond", rate, "handshakes");
+    }
+    for threads in [1, 4] {
+        let name = format!("loopback x{threads}");
+        if enabled(&name) {
+            report(&name, loopback(threads), "handshakes");
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::{fmt, sync::Arc};
+
+use crate::{
+    base64,
+    handshake::{Request, Response},
+    token::Signer,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Basic { user: String, password: String },
+    Bearer(String),
+}
+
+impl Credentials {
+    pub fn parse(authorization: &str) -> Option<Self> {
+        let (scheme, value) = authorization.trim().split_once(' ')?;
+        let value = value.trim();
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let decoded = String::from_utf8(base64::decode(value).ok()?).ok()?;
+            let (user, password) = decoded.split_once(':')?;
+            Some(Self::Basic {
+                user: user.to_string(),
+                password: password.to_string(),
+            })
+        } else if scheme.eq_ignore_ascii_case("Bearer") && !value.is_empty() {
+            Some(Self::Bearer(value.to_string()))
+        } else {
+            None
+        }
+    }
+
+    // The Authorization header value, for clients.
+    pub fn header(&self) -> String {
+        match self {
+            Self::Basic { user, password } => {
+                format!(
+                    "Basic {}",
+                    base64::encode(format!("{user}:{password}").as_bytes())
+                )
+            }
+            Self::Bearer(token) => format!("Bearer {token}"),
+        }
+    }
+}
+
+pub(crate) fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+pub(crate) fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            // from_str_radix alone would take a sign, as in "%+5".
+            let hex = bytes.get(index + 1..index + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// The percent-decoded value of `name` in the request's query string.
+pub fn query_param(path: &str, name: &str) -> Option<String> {
+    let (_, query) = path.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| percent_decode(value))
+}
+
+pub type Verifier = Arc<dyn Fn(&Credentials) -> Option<String> + Send + Sync>;
+
+// Handshake authentication: credentials come from the Authorization header
+// or, for browsers that cannot set headers, from the `query` parameter as a
+// bearer token. The verifier returns the principal, which the connection
+// carries in `Peer::principal`; failures are answered with 401.
+#[derive(Clone)]
+pub struct Auth {
+    pub realm: String,
+    pub basic: bool,
+    pub bearer: bool,
+    pub query: Option<String>,
+    pub verifier: Verifier,
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auth")
+            .field("realm", &self.realm)
+            .field("basic", &self.basic)
+            .field("bearer", &self.bearer)
+            .field("query", &self.query)
+            .finish()
+    }
+}
+
+impl Auth {
+    pub fn new<F>(realm: &str, verifier: F) -> Self
+    where
+        F: Fn(&Credentials) -> Option<String> + Send + Sync + 'static,
+    {
+        Self {
+            realm: realm.to_string(),
+            basic: true,
+            bearer: true,
+            query: Some("token".to_string()),
+            verifier: Arc::new(verifier),
+        }
+    }
+
+    // Accepts bearer tokens issued by `signer`; the principal is their subject.
+    pub fn signed(realm: &str, signer: Signer) -> Self {
+        let verifier = move |credentials: &Credentials| match credentials {
+            Credentials::Bearer(token) => signer.verify(token).ok().map(|claims| claims.subject),
+            Credentials::Basic { .. } => None,
+        };
+        Self {
+            basic: false,
+            ..Self::new(realm, verifier)
+        }
+    }
+
+    pub fn credentials(&self, request: &Request) -> Option<Credentials> {
+        let from_header = request
+            .header("Authorization")
+            .and_then(Credentials::parse)
+            .filter(|credentials| match credentials {
+                Credentials::Basic { .. } => self.basic,
+                Credentials::Bearer(_) => self.bearer,
+            });
+        from_header.or_else(|| {
+            let name = self.query.as_deref().filter(|_| self.bearer)?;
+            query_param(&request.path, name).map(Credentials::Bearer)
+        })
+    }
+
+    pub fn authenticate(&self, request: &Request) -> Result<String, Response> {
+        let credentials = self.credentials(request);
+        if let Some(principal) = credentials.as_ref().and_then(|c| (self.verifier)(c)) {
+            return Ok(principal);
+        }
+        let mut response = Response::new(401);
+        if self.basic {
+            let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm);
+            response = response.header("WWW-Authenticate", &challenge);
+        }
+        if self.bearer {
+            let mut challenge = format!("Bearer realm=\"{}\"", self.realm);
+            if let Some(Credentials::Bearer(_)) = credentials {
+                challenge.push_str(", error=\"invalid_token\"");
+            }
+            response = response.header("WWW-Authenticate", &challenge);
+        }
+        Err(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{query_param, Auth, Credentials};
+    use crate::{
+        handshake::Request,
+        token::{Claims, Signer},
+    };
+
+    fn request(path: &str, authorization: Option<&str>) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            headers: authorization
+                .map(|value| ("Authorization".to_string(), value.to_string()))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn credentials() {
+        let basic = Credentials::Basic {
+            user: "Aladdin".into(),
+            password: "open sesame".into(),
+        };
+        assert_eq!(basic.header(), "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==");
+        assert_eq!(Credentials::parse(&basic.header()), Some(basic));
+        assert_eq!(
+            Credentials::parse("bearer  abc.def "),
+            Some(Credentials::Bearer("abc.def".into()))
+        );
+        assert_eq!(Credentials::parse("Digest x"), None);
+        assert_eq!(Credentials::parse("Basic bm9jb2xvbg=="), None);
+        assert_eq!(
+            query_param("/ws?a=1&token=x%2By%3D", "token").as_deref(),
+            Some("x+y=")
+        );
+        assert_eq!(query_param("/ws?token=%zz", "token"), None);
+        assert_eq!(query_param("/ws?token=%+5", "token"), None);
+        assert_eq!(query_param("/ws", "token"), None);
+    }
+
+    #[test]
+    fn authenticate() {
+        let auth = Auth::new("tools", |credentials| match credentials {
+            Credentials::Basic { user, password } if password == "pw" => Some(user.clone()),
+            Credentials::Bearer(token) if token == "t0k" => Some("service".to_string()),
+            _ => None,
+        });
+        let basic = Credentials::Basic {
+            user: "ann".into(),
+            password: "pw".into(),
+        };
+        assert_eq!(
+            auth.authenticate(&request("/", Some(&basic.header()))),
+            Ok("ann".into())
+        );
+        assert_eq!(
+            auth.authenticate(&request("/", Some("Bearer t0k"))),
+            Ok("service".into())
+        );
+        assert_eq!(
+            auth.authenticate(&request("/?token=t0k", None)),
+            Ok("service".into())
+        );
+
+        let missing = auth.authenticate(&request("/", None)).unwrap_err();
+        assert_eq!(missing.status, 401);
+        let challenges: Vec<&str> = missing
+            .headers
+            .iter()
+            .filter(|(name, _)| name == "WWW-Authenticate")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(
+            challenges,
+            [
+                "Basic realm=\"tools\", charset=\"UTF-8\"",
+                "Bearer realm=\"tools\""
+            ]
+        );
+        let invalid = auth
+            .authenticate(&request("/?token=bad", None))
+            .unwrap_err();
+        assert!(invalid.headers[1].1.ends_with("error=\"invalid_token\""));
+
+        let bearer_only = Auth {
+            basic: false,
+            ..auth
+        };
+        assert!(bearer_only
+            .authenticate(&request("/", Some(&basic.header())))
+            .is_err());
+    }
+
+    #[test]
+    fn signed_tokens() {
+        let signer = Signer::new(b"k");
+        let token = signer.sign(&Claims::new("ann", Duration::from_secs(60)));
+        let auth = Auth::signed("tools", signer);
+        let path = format!("/ws?token={token}");
+        assert_eq!(auth.authenticate(&request(&path, None)), Ok("ann".into()));
+        let header = format!("Bearer {token}x");
+        assert!(auth.authenticate(&request("/", Some(&header))).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use std::{error::Error, fmt};
 
 pub const ALPHABET: [u8; 64] = [
     b'A', b'B', b'C', b'D', b'E', b'F', b'G', b'H', b'I', b'J', b'K', b'L', b'M', b'N', b'O', b'P',
@@ -6,86 +7,312 @@ pub const ALPHABET: [u8; 64] = [
     b'w', b'x', b'y', b'z', b'0', b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'+', b'/',
 ];
 
-pub fn encode(input: &[u8]) -> String {
-    let mut output = String::new();
-    let l = input.len() - input.len() % 3;
-    let rem = input.len() - l;
-    let mut index = 0;
-    let mut out_index = 0;
-    loop {
-        let chunk = &input[index..index+3];
-        index += 3;
-        let char_1 = chunk[0];
-        let char_2 = chunk[1];
-        let char_3 = chunk[2];
-        let integer = u32::from_be_bytes([char_1, char_2, char_3, 0]);
-        for i in 0..4 {
-            let idx = (integer >> (26 - i * 6)) & 0x3f;
-            output.push(ALPHABET[idx as usize] as char) ;
-            out_index += 1;
+// RFC 4648 section 5: '-' and '_' replace '+' and '/'.
+pub const URL_ALPHABET: [u8; 64] = {
+    let mut symbols = ALPHABET;
+    symbols[62] = b'-';
+    symbols[63] = b'_';
+    symbols
+};
+
+const INVALID: u8 = 0xff;
+
+// Accepts either alphabet; used by the lenient decoder.
+const ANY: [u8; 256] = {
+    let mut table = STANDARD.values;
+    table[b'-' as usize] = 62;
+    table[b'_' as usize] = 63;
+    table
+};
+
+pub const STANDARD: Alphabet = Alphabet::new(ALPHABET, true);
+pub const URL_SAFE: Alphabet = Alphabet::new(URL_ALPHABET, false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Base64Error {
+    // Offset and value of a byte outside the alphabet, or of a final
+    // symbol whose unused low bits are not zero.
+    InvalidByte(usize, u8),
+    InvalidLength,
+    InvalidPadding,
+    BufferTooSmall,
+}
+
+impl fmt::Display for Base64Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::InvalidByte(offset, byte) => {
+                return write!(f, "invalid base64 byte {byte:#04x} at offset {offset}")
+            }
+            Self::InvalidLength => "invalid base64 length",
+            Self::InvalidPadding => "invalid base64 padding",
+            Self::BufferTooSmall => "output buffer too small",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl Error for Base64Error {}
+
+// A symbol table plus whether encoded text carries '=' padding. Decoding
+// is strict: only the alphabet's symbols, padding exactly where the
+// encoder would put it, and zero bits left over in the final symbol.
+#[derive(Debug, Clone, Copy)]
+pub struct Alphabet {
+    symbols: [u8; 64],
+    values: [u8; 256],
+    padding: bool,
+}
+
+impl Alphabet {
+    pub const fn new(symbols: [u8; 64], padding: bool) -> Self {
+        let mut values = [INVALID; 256];
+        let mut index = 0;
+        while index < symbols.len() {
+            values[symbols[index] as usize] = index as u8;
+            index += 1;
         }
-        if index == l {
-            break;
+        Self {
+            symbols,
+            values,
+            padding,
         }
     }
 
-    if rem == 2 {
-        output.push(ALPHABET[(input[index] >> 2) as usize] as char);
-        output.push(ALPHABET[((input[index] & 3) << 4 | (input[index+1] >> 4)) as usize] as char);
-        output.push(ALPHABET[((input[index+1] & 0xf) << 2)as usize] as char);
-        out_index += 3;
-    } else if rem == 1 {
-        output.push(ALPHABET[(input[index] >> 2) as usize] as char);
-        output.push(ALPHABET[((input[index] & 3) << 4) as usize] as char);
-        out_index += 2;
+    pub fn encoded_len(&self, len: usize) -> usize {
+        if self.padding {
+            len.div_ceil(3) * 4
+        } else {
+            len / 3 * 4 + [0, 2, 3][len % 3]
+        }
     }
 
-    if rem > 0 {
-        for _ in 0..(3 - rem) {
-            output.push(b'=' as char);
-            out_index += 1;
-        } 
+    // Writes the encoding to the front of `output`; returns its length.
+    pub fn encode_slice(&self, input: &[u8], output: &mut [u8]) -> Result<usize, Base64Error> {
+        let len = self.encoded_len(input.len());
+        let output = output.get_mut(..len).ok_or(Base64Error::BufferTooSmall)?;
+        let symbol = |n: u32| self.symbols[n as usize & 63];
+
+        let mut chunks = input.chunks_exact(3);
+        let mut quads = output.chunks_exact_mut(4);
+        for (chunk, quad) in (&mut chunks).zip(&mut quads) {
+            let n = u32::from_be_bytes([0, chunk[0], chunk[1], chunk[2]]);
+            quad.copy_from_slice(&[symbol(n >> 18), symbol(n >> 12), symbol(n >> 6), symbol(n)]);
+        }
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            let n = u32::from_be_bytes([0, rest[0], rest.get(1).copied().unwrap_or(0), 0]);
+            let tail = &mut output[input.len() / 3 * 4..];
+            tail[0] = symbol(n >> 18);
+            tail[1] = symbol(n >> 12);
+            if rest.len() == 2 {
+                tail[2] = symbol(n >> 6);
+            }
+            if self.padding {
+                tail[rest.len() + 1..].fill(b'=');
+            }
+        }
+        Ok(len)
     }
-    output
-}
 
-pub fn decode(input: &str) -> Vec<u8> {
-    let mut lookup = [0u8; 256];
-    for (index, c) in ALPHABET.iter().enumerate() {
-        lookup[*c as usize] = index as u8;
+    pub fn encode(&self, input: &[u8]) -> String {
+        let mut output = vec![0; self.encoded_len(input.len())];
+        self.encode_slice(input, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
     }
-    let mut output = vec![];
-    let mut acc:u64 = 0;
-    let mut acc_len = 0;
-    for (_, c) in input.bytes().enumerate() {
-        if c == b'=' {
-            break;
+
+    // An upper bound on the decoded length, for sizing `decode_slice` output.
+    pub fn decoded_len_estimate(&self, len: usize) -> usize {
+        len.div_ceil(4) * 3
+    }
+
+    // Writes the decoded bytes to the front of `output`; returns their count.
+    pub fn decode_slice(&self, input: &[u8], output: &mut [u8]) -> Result<usize, Base64Error> {
+        let body = self.unpad(input)?;
+        if body.len() % 4 == 1 {
+            return Err(Base64Error::InvalidLength);
         }
-        acc = acc << 6 | ((lookup[c as usize] as u64) & 63);
-        acc_len += 1;
-        if acc_len == 8 {
-            for i in 0..6 {
-                output.push(((acc >> (40 - (8 * i))) & 255) as u8);
+        let len = body.len() / 4 * 3 + body.len() % 4 * 3 / 4;
+        let output = output.get_mut(..len).ok_or(Base64Error::BufferTooSmall)?;
+        let value = |offset: usize| match self.values[body[offset] as usize] {
+            INVALID => Err(Base64Error::InvalidByte(offset, body[offset])),
+            value => Ok(value as u32),
+        };
+
+        let mut offset = 0;
+        for triple in output.chunks_exact_mut(3) {
+            let n = value(offset)? << 18
+                | value(offset + 1)? << 12
+                | value(offset + 2)? << 6
+                | value(offset + 3)?;
+            triple.copy_from_slice(&n.to_be_bytes()[1..]);
+            offset += 4;
+        }
+        let rest = body.len() - offset;
+        if rest > 0 {
+            let mut n = 0;
+            for index in 0..rest {
+                n |= value(offset + index)? << (18 - 6 * index);
+            }
+            let last = offset + rest - 1;
+            if n.to_be_bytes()[rest] != 0 {
+                return Err(Base64Error::InvalidByte(last, body[last]));
             }
-            acc_len = 0;
+            output[len - (rest - 1)..].copy_from_slice(&n.to_be_bytes()[1..rest]);
         }
+        Ok(len)
     }
 
-    if acc_len > 0 {
-        let bits = acc_len * 6;
-        let bytes = bits/8;
-        acc >>= bits % 8; 
-        if bytes != 0 {
-            for i in 0..bytes {
-                output.push((acc >> (((bytes * 8) - 8) - (8 * i)) & 255) as u8);
-            }
+    pub fn decode(&self, input: &str) -> Result<Vec<u8>, Base64Error> {
+        let mut output = vec![0; self.decoded_len_estimate(input.len())];
+        let len = self.decode_slice(input.as_bytes(), &mut output)?;
+        output.truncate(len);
+        Ok(output)
+    }
+
+    // The symbols without their padding, once the padding is checked.
+    fn unpad<'a>(&self, input: &'a [u8]) -> Result<&'a [u8], Base64Error> {
+        let pad = input.iter().rev().take_while(|&&c| c == b'=').count();
+        let body = &input[..input.len() - pad];
+        if body.contains(&b'=') {
+            return Err(Base64Error::InvalidPadding);
+        }
+        if !self.padding {
+            return match pad {
+                0 => Ok(body),
+                _ => Err(Base64Error::InvalidPadding),
+            };
+        }
+        if !input.len().is_multiple_of(4) {
+            return Err(Base64Error::InvalidLength);
         }
+        if pad > 2 {
+            return Err(Base64Error::InvalidPadding);
+        }
+        Ok(body)
     }
+}
+
+pub fn encode(input: &[u8]) -> String {
+    STANDARD.encode(input)
+}
+
+pub fn decode(input: &str) -> Result<Vec<u8>, Base64Error> {
+    STANDARD.decode(input)
+}
+
+pub fn encode_url(input: &[u8]) -> String {
+    URL_SAFE.encode(input)
+}
+
+pub fn decode_url(input: &str) -> Result<Vec<u8>, Base64Error> {
+    URL_SAFE.decode(input)
+}
 
+// Decodes whatever can be decoded: either alphabet, padding optional and
+// ending the input, other bytes such as line breaks skipped, and leftover
+// bits ignored.
+pub fn decode_lenient(input: &str) -> Vec<u8> {
+    let sextets: Vec<u32> = input
+        .bytes()
+        .take_while(|&c| c != b'=')
+        .filter_map(|c| match ANY[c as usize] {
+            INVALID => None,
+            value => Some(value as u32),
+        })
+        .collect();
+    let mut output = Vec::with_capacity(sextets.len() * 3 / 4);
+    let mut quads = sextets.chunks_exact(4);
+    for quad in &mut quads {
+        let n = quad[0] << 18 | quad[1] << 12 | quad[2] << 6 | quad[3];
+        output.extend_from_slice(&n.to_be_bytes()[1..]);
+    }
+    let rest = quads.remainder();
+    let n = rest
+        .iter()
+        .enumerate()
+        .fold(0, |n, (index, &value)| n | value << (18 - 6 * index));
+    output.extend_from_slice(&n.to_be_bytes()[1..1 + rest.len() * 6 / 8]);
     output
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{
+        decode, decode_lenient, decode_url, encode, encode_url, Base64Error, STANDARD, URL_SAFE,
+    };
+
+    #[test]
+    fn rfc_4648() {
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (plain, encoded) in cases {
+            assert_eq!(encode(plain.as_bytes()), encoded);
+            assert_eq!(decode(encoded).unwrap(), plain.as_bytes());
+            let unpadded = encoded.trim_end_matches('=');
+            assert_eq!(encode_url(plain.as_bytes()), unpadded);
+            assert_eq!(decode_url(unpadded).unwrap(), plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let data: Vec<u8> = (0..=255u8).rev().cycle().take(300).collect();
+        for len in 0..data.len() {
+            assert_eq!(decode(&encode(&data[..len])).unwrap(), &data[..len]);
+            assert_eq!(decode_url(&encode_url(&data[..len])).unwrap(), &data[..len]);
+            assert_eq!(decode_lenient(&encode(&data[..len])), &data[..len]);
+        }
+        assert_eq!(encode_url(&[0xfb, 0xff]), "-_8");
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn strict_errors() {
+        assert_eq!(decode("Zm9v!mFy"), Err(Base64Error::InvalidByte(4, b'!')));
+        assert_eq!(decode("Zm9vYg="), Err(Base64Error::InvalidLength));
+        assert_eq!(decode("Zm9vY"), Err(Base64Error::InvalidLength));
+        assert_eq!(decode("Zm=vYg=="), Err(Base64Error::InvalidPadding));
+        assert_eq!(decode("Zm9vY==="), Err(Base64Error::InvalidPadding));
+        assert_eq!(decode("Zh=="), Err(Base64Error::InvalidByte(1, b'h')));
+        assert_eq!(decode("-_8="), Err(Base64Error::InvalidByte(0, b'-')));
+        assert_eq!(decode_url("Zm8="), Err(Base64Error::InvalidPadding));
+        assert_eq!(decode_url("Zm9vY"), Err(Base64Error::InvalidLength));
+        assert_eq!(decode_url("+/8"), Err(Base64Error::InvalidByte(0, b'+')));
+    }
+
+    #[test]
+    fn lenient() {
+        assert_eq!(decode_lenient("Zm9v\r\nYmFy"), b"foobar");
+        assert_eq!(decode_lenient("Zm9vYg"), b"foob");
+        assert_eq!(decode_lenient("Zh=="), b"f");
+        assert_eq!(decode_lenient("-_8"), [0xfb, 0xff]);
+        assert_eq!(decode_lenient("Zm8=garbage"), b"fo");
+    }
+
+    #[test]
+    fn caller_buffers() {
+        let mut buffer = [0u8; 8];
+        assert_eq!(STANDARD.encode_slice(b"fooba", &mut buffer), Ok(8));
+        assert_eq!(&buffer, b"Zm9vYmE=");
+        assert_eq!(URL_SAFE.encode_slice(b"fooba", &mut buffer), Ok(7));
+        assert_eq!(
+            STANDARD.encode_slice(b"foobar!", &mut buffer),
+            Err(Base64Error::BufferTooSmall)
+        );
+
+        let mut output = [0u8; 5];
+        assert_eq!(STANDARD.decode_slice(b"Zm9vYmE=", &mut output), Ok(5));
+        assert_eq!(&output, b"fooba");
+        assert_eq!(
+            STANDARD.decode_slice(b"Zm9vYmFy", &mut output),
+            Err(Base64Error::BufferTooSmall)
+        );
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    env,
+    io::{self, BufRead, ErrorKind, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    process,
+    sync::{
+        mpsc::{self, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use weso::{
+    base64,
+    client::{Client, ClientConfig},
+    compression::DeflateConfig,
+    message::Message,
+    stream::Transport,
+};
+
+const USAGE: &str = "\
+Usage: client [OPTIONS] URL
+
+Connects to URL (ws://HOST[:PORT]/PATH or ws+unix://SOCKET[:/PATH]), sends
+each line read from stdin as a message and prints received messages.
+
+Options:
+  -b, --binary FORMAT       send lines as binary messages decoded from FORMAT
+                            (hex or base64); received binary uses it too
+  -H, --header 'NAME: VALUE'
+                            add a request header; repeatable
+  -p, --protocol NAME       offer subprotocol NAME; repeatable
+      --ping SECS           send a ping every SECS seconds
+      --deflate             offer permessage-deflate
+      --pipe                bridge raw stdin/stdout to the connection instead
+                            of reading lines
+      --listen ADDR         accept TCP connections on ADDR and bridge each one
+                            over its own WebSocket connection
+  -h, --help                print this help
+
+In pipe and listen modes every chunk read is sent as a binary message, the
+payloads received are written out unchanged and end of input closes the
+connection normally.
+
+Commands:
+  /ping [TEXT]              send a ping
+  /close [CODE [REASON]]    start the closing handshake
+  //TEXT                    send a line starting with a slash";
+
+const TICK: Duration = Duration::from_millis(50);
+const CHUNK: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Hex,
+    Base64,
+}
+
+impl Format {
+    fn encode(self, data: &[u8]) -> String {
+        match self {
+            Self::Hex => data.iter().map(|byte| format!("{byte:02x}")).collect(),
+            Self::Base64 => base64::encode(data),
+        }
+    }
+
+    fn decode(self, line: &str) -> Option<Vec<u8>> {
+        match self {
+            Self::Hex => {
+                let digits: Vec<u8> = line.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+                if !digits.len().is_multiple_of(2) {
+                    return None;
+                }
+                digits
+                    .chunks(2)
+                    .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+                    .collect()
+            }
+            Self::Base64 => base64::decode(line.trim()).ok(),
+        }
+    }
+}
+
+enum Bridge {
+    Lines,
+    Stdio,
+    Listen(String),
+}
+
+struct Options {
+    url: String,
+    binary: Option<Format>,
+    ping: Option<Duration>,
+    bridge: Bridge,
+    config: ClientConfig,
+}
+
+fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>, String> {
+    let mut url = None;
+    let mut binary = None;
+    let mut ping = None;
+    let mut bridge = Bridge::Lines;
+    let mut config = ClientConfig::default();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg, None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("missing value for {flag}"))
+        };
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-b" | "--binary" => {
+                binary = match value()?.as_str() {
+                    "hex" => Some(Format::Hex),
+                    "base64" => Some(Format::Base64),
+                    other => return Err(format!("unknown binary format: {other}")),
+                }
+            }
+            "-H" | "--header" => {
+                let header = value()?;
+                let (name, value) = header
+                    .split_once(':')
+                    .ok_or_else(|| format!("invalid header: {header}"))?;
+                config
+                    .headers
+                    .push((name.trim().to_string(), value.trim().to_string()));
+            }
+            "-p" | "--protocol" => config.protocols.push(value()?),
+            "--ping" => {
+                let seconds = value()?;
+                ping = seconds
+                    .parse()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .filter(|every| !every.is_zero());
+                if ping.is_none() {
+                    return Err(format!("invalid value for --ping: {seconds}"));
+                }
+            }
+            "--deflate" => config.deflate = Some(DeflateConfig::default()),
+            "--pipe" => bridge = Bridge::Stdio,
+            "--listen" => bridge = Bridge::Listen(value()?),
+            other if other.starts_with('-') => return Err(format!("unknown option: {other}")),
+            _ if url.is_none() => url = Some(flag),
+            other => return Err(format!("unexpected argument: {other}")),
+        }
+    }
+    let url = url.ok_or("missing URL")?;
+    Ok(Some(Options {
+        url,
+        binary,
+        ping,
+        bridge,
+        config,
+    }))
+}
+
+fn stdin_lines() -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// Turns one input line into the message to send.
+fn command(line: &str, binary: Option<Format>) -> Result<Message, String> {
+    if let Some(text) = line.strip_prefix("//") {
+        return Ok(Message::text(&format!("/{text}")));
+    }
+    if let Some(rest) = line.strip_prefix('/') {
+        let (name, args) = rest.split_once(' ').unwrap_or((rest, ""));
+        return match name {
+            "ping" => Ok(Message::Ping(args.as_bytes().to_vec())),
+            "close" => {
+                let (code, reason) = args.split_once(' ').unwrap_or((args, ""));
+                let code = match code {
+                    "" => 1000,
+                    code => code
+                        .parse()
+                        .map_err(|_| format!("invalid close code: {code}"))?,
+                };
+                Ok(Message::close(code, reason))
+            }
+            _ => Err(format!("unknown command: /{name}")),
+        };
+    }
+    match binary {
+        Some(format) => format
+            .decode(line)
+            .map(Message::Binary)
+            .ok_or_else(|| format!("invalid {format:?} input")),
+        None => Ok(Message::text(line)),
+    }
+}
+
+fn repl<S: Transport>(mut client: Client<S>, options: &Options) -> io::Result<()> {
+    client.stream().set_read_timeout(Some(TICK))?;
+    let lines = stdin_lines();
+    let mut next_ping = options.ping.map(|every| Instant::now() + every);
+    let mut closing = false;
+    loop {
+        while !closing {
+            let line = match lines.try_recv() {
+                Ok(line) => line,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    client.close(1000, "")?;
+                    closing = true;
+                    break;
+                }
+            };
+            match command(&line, options.binary) {
+                Ok(message) => {
+                    closing = matches!(message, Message::Close(_));
+                    client.send(&message)?;
+                }
+                Err(e) => eprintln!("{e}"),
+            }
+        }
+        if let (Some(at), Some(every)) = (next_ping, options.ping) {
+            if !closing && Instant::now() >= at {
+                client.send(&Message::Ping(vec![]))?;
+                next_ping = Some(at + every);
+            }
+        }
+
+        match client.recv() {
+            Ok(Message::Text(text)) => println!("{text}"),
+            Ok(Message::Binary(data)) => {
+                println!("{}", options.binary.unwrap_or(Format::Hex).encode(&data))
+            }
+            Ok(Message::Ping(_)) => eprintln!("< ping"),
+            Ok(Message::Pong(_)) => eprintln!("< pong"),
+            Ok(Message::Close(Some((status, reason)))) => {
+                eprintln!("closed: {status} {reason}");
+                return Ok(());
+            }
+            Ok(Message::Close(None)) => {
+                eprintln!("closed");
+                return Ok(());
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn chunks<R: Read + Send + 'static>(mut reader: R) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = vec![0u8; CHUNK];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) if tx.send(buffer[..n].to_vec()).is_ok() => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                _ => break,
+            }
+        }
+    });
+    rx
+}
+
+// Carries input chunks out as binary messages and writes received payloads
+// to `output` until either side closes.
+fn pipe<S: Transport, W: Write>(
+    mut client: Client<S>,
+    input: mpsc::Receiver<Vec<u8>>,
+    mut output: W,
+) -> io::Result<()> {
+    client.stream().set_read_timeout(Some(TICK))?;
+    let mut closing = false;
+    loop {
+        while !closing {
+            match input.try_recv() {
+                Ok(chunk) => client.send(&Message::Binary(chunk))?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    client.close(1000, "")?;
+                    closing = true;
+                }
+            }
+        }
+        match client.recv() {
+            Ok(Message::Binary(data)) => {
+                output.write_all(&data)?;
+                output.flush()?;
+            }
+            Ok(Message::Text(text)) => {
+                output.write_all(text.as_bytes())?;
+                output.flush()?;
+            }
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn session<S: Transport>(
+    client: Client<S>,
+    options: &Options,
+    tcp: Option<TcpStream>,
+) -> io::Result<()> {
+    match (&options.bridge, tcp) {
+        (_, Some(stream)) => {
+            let input = chunks(stream.try_clone()?);
+            let result = pipe(client, input, &stream);
+            let _ = stream.shutdown(Shutdown::Both);
+            result
+        }
+        (Bridge::Stdio, None) => pipe(client, chunks(io::stdin()), io::stdout()),
+        _ => repl(client, options),
+    }
+}
+
+fn connect(options: &Options, tcp: Option<TcpStream>) -> io::Result<()> {
+    if options.url.starts_with("ws+unix://") {
+        let client = Client::connect_unix_with(&options.url, &options.config)?;
+        report(client.protocol(), &client.extensions());
+        session(client, options, tcp)
+    } else {
+        let client = Client::connect_with(&options.url, &options.config)?;
+        report(client.protocol(), &client.extensions());
+        session(client, options, tcp)
+    }
+}
+
+fn run(options: Options) -> io::Result<()> {
+    let addr = match &options.bridge {
+        Bridge::Listen(addr) => addr.clone(),
+        _ => return connect(&options, None),
+    };
+    let listener = TcpListener::bind(&addr)?;
+    eprintln!("listening on {}", listener.local_addr()?);
+    let options = Arc::new(options);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let options = options.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            if let Err(e) = connect(&options, Some(stream)) {
+                eprintln!("client: {peer:?}: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn report(protocol: Option<&str>, extensions: &[&str]) {
+    eprint!("connected");
+    if let Some(protocol) = protocol {
+        eprint!(", protocol {protocol}");
+    }
+    if !extensions.is_empty() {
+        eprint!(", extensions {}", extensions.join(", "));
+    }
+    eprintln!();
+}
+
+fn main() {
+    let options = match parse(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            return;
+        }
+        Err(e) => {
+            eprintln!("client: {e}\n\n{USAGE}");
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(options) {
+        eprintln!("client: {e}");
+        process::exit(1);
+    }
+}
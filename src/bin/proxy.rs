@@ -0,0 +1,601 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    env,
+    hash::{Hash, Hasher},
+    io::{self, IoSlice, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use weso::{
+    client::{Client, ClientConfig, Refused},
+    frame::Opcode,
+    handshake::{Request, Response},
+    listener::{Listener, UnixSocket},
+    message::Message,
+    random,
+    server::{Config, ConnectionId, Handler, Peer, Server, ServerHandle},
+    signal::{SIGINT, SIGTERM},
+};
+
+const USAGE: &str = "\
+Usage: proxy [OPTIONS]
+
+Accepts WebSocket connections and relays each one to a backend server.
+
+Options:
+  -b, --bind ADDR             listen on ADDR (host:port or unix:PATH); repeatable
+                              [default: 127.0.0.1:8080]
+      --backend URL           add ws://HOST[:PORT] to the default pool; repeatable
+      --route PREFIX=URL[,URL...]
+                              send paths starting with PREFIX to these backends;
+                              the longest matching prefix wins
+      --hash-header NAME      pin clients to a backend by hashing header NAME
+      --hash-cookie NAME      pin clients to a backend by hashing cookie NAME
+      --health-interval SECS  probe backends every SECS seconds, 0 to disable
+                              [default: 5]
+  -p, --protocol NAME         accept subprotocol NAME and offer it upstream;
+                              repeatable
+  -v, --verbose               log connections and backend choices
+  -q, --quiet                 only log errors
+  -h, --help                  print this help";
+
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// A backend that cannot accept within this is skipped for the next one.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const VIRTUAL_NODES: usize = 64;
+
+// Handshake headers the proxy negotiates itself on each leg.
+const HOP_HEADERS: [&str; 8] = [
+    "host",
+    "upgrade",
+    "connection",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-protocol",
+    "sec-websocket-extensions",
+    "x-forwarded-for",
+];
+
+struct Backend {
+    url: String,
+    host: String,
+    addr: String,
+    healthy: AtomicBool,
+}
+
+impl Backend {
+    fn new(url: &str) -> Result<Self, String> {
+        let rest = url
+            .strip_prefix("ws://")
+            .ok_or_else(|| format!("unsupported backend url: {url}"))?;
+        let authority = rest.split('/').next().unwrap_or_default();
+        if authority.is_empty() {
+            return Err(format!("missing backend host: {url}"));
+        }
+        let addr = match authority.rsplit_once(':') {
+            Some((_, port)) if !authority.ends_with(']') && port.parse::<u16>().is_ok() => {
+                authority.to_string()
+            }
+            _ => format!("{authority}:80"),
+        };
+        Ok(Self {
+            url: format!("ws://{authority}"),
+            host: authority.to_string(),
+            addr,
+            healthy: AtomicBool::new(true),
+        })
+    }
+
+    // Tries each resolved address in turn, so an unresponsive backend
+    // costs a bounded wait rather than the system's connect timeout.
+    fn dial(&self) -> io::Result<TcpStream> {
+        let mut last = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+        for addr in self.addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+
+    fn probe(&self) -> bool {
+        let reachable = self.dial().is_ok();
+        self.healthy.store(reachable, Ordering::Relaxed);
+        reachable
+    }
+}
+
+fn hash<T: Hash>(value: T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+// A path prefix and the backends serving it, with a consistent hash ring
+// so adding or losing a backend only moves the keys that mapped to it.
+struct Route {
+    prefix: String,
+    backends: Vec<usize>,
+    ring: Vec<(u64, usize)>,
+}
+
+impl Route {
+    fn new(prefix: String, backends: Vec<usize>, pool: &[Backend]) -> Self {
+        let mut ring: Vec<(u64, usize)> = backends
+            .iter()
+            .flat_map(|&index| (0..VIRTUAL_NODES).map(move |node| (index, node)))
+            .map(|(index, node)| (hash((&pool[index].url, node)), index))
+            .collect();
+        ring.sort_unstable();
+        Self {
+            prefix,
+            backends,
+            ring,
+        }
+    }
+
+    // Backends in the order they should be tried.
+    fn candidates(&self, key: Option<&str>, turn: usize) -> Vec<usize> {
+        let mut order = vec![];
+        match key {
+            Some(key) => {
+                let start = self.ring.partition_point(|(point, _)| *point < hash(key));
+                for offset in 0..self.ring.len() {
+                    let (_, index) = self.ring[(start + offset) % self.ring.len()];
+                    if !order.contains(&index) {
+                        order.push(index);
+                    }
+                }
+            }
+            None => {
+                let count = self.backends.len();
+                order.extend((0..count).map(|offset| self.backends[(turn + offset) % count]));
+            }
+        }
+        order
+    }
+}
+
+#[derive(Clone)]
+enum Affinity {
+    None,
+    Header(String),
+    Cookie(String),
+}
+
+impl Affinity {
+    fn key<'a>(&self, request: &'a Request) -> Option<&'a str> {
+        match self {
+            Self::None => None,
+            Self::Header(name) => request.header(name),
+            Self::Cookie(name) => request
+                .header_values("Cookie")
+                .into_iter()
+                .flat_map(|cookies| cookies.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value),
+        }
+    }
+}
+
+struct Shared {
+    pool: Vec<Backend>,
+    routes: Vec<Route>,
+    affinity: Affinity,
+    turn: AtomicUsize,
+    upstreams: Mutex<HashMap<ConnectionId, mpsc::Sender<Message>>>,
+    verbosity: u8,
+}
+
+impl Shared {
+    fn route(&self, path: &str) -> Option<&Route> {
+        let path = path.split('?').next().unwrap_or_default();
+        self.routes
+            .iter()
+            .filter(|route| path.starts_with(&route.prefix))
+            .max_by_key(|route| route.prefix.len())
+    }
+}
+
+#[derive(Clone)]
+struct Proxy(Arc<Shared>);
+
+fn upstream_config(peer: &Peer) -> ClientConfig {
+    let request = &peer.request;
+    let mut headers: Vec<(String, String)> = request
+        .headers
+        .iter()
+        .filter(|(name, _)| !HOP_HEADERS.contains(&name.to_ascii_lowercase().as_str()))
+        .cloned()
+        .collect();
+    if let Some(ip) = peer.addr.ip() {
+        let forwarded = match request.header("X-Forwarded-For") {
+            Some(previous) => format!("{previous}, {ip}"),
+            None => ip.to_string(),
+        };
+        headers.push(("X-Forwarded-For".to_string(), forwarded));
+    }
+    ClientConfig {
+        headers,
+        protocols: peer.protocol.iter().cloned().collect(),
+        ..ClientConfig::default()
+    }
+}
+
+// The write side of an upstream connection, shared by the relay's writer
+// thread and the client's own pongs and close echoes. Frames come in as one
+// header and payload pair and are written whole under the lock; nothing
+// goes out after a close frame.
+struct Outbound {
+    stream: TcpStream,
+    closed: bool,
+}
+
+impl Write for Outbound {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        if self.closed {
+            return Ok(len);
+        }
+        self.closed = bufs
+            .first()
+            .and_then(|header| header.first())
+            .is_some_and(|&byte| Opcode::from(byte & 0x0f) == Opcode::Close);
+        for buf in bufs {
+            self.stream.write_all(buf)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// The upstream socket as the client sees it: reads go straight to the
+// socket, writes through the shared outbound side.
+struct Upstream {
+    stream: TcpStream,
+    outbound: Arc<Mutex<Outbound>>,
+}
+
+impl Upstream {
+    fn connect(backend: &Backend, path: &str, config: &ClientConfig) -> io::Result<Client<Self>> {
+        let stream = backend.dial()?;
+        let outbound = Outbound {
+            stream: stream.try_clone()?,
+            closed: false,
+        };
+        let upstream = Self {
+            stream,
+            outbound: Arc::new(Mutex::new(outbound)),
+        };
+        Client::handshake(upstream, &backend.host, path, config)
+    }
+}
+
+impl Read for Upstream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Upstream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outbound.lock().unwrap().write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.outbound.lock().unwrap().write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.outbound.lock().unwrap().flush()
+    }
+}
+
+// Unreachable backends and 5xx answers count against the backend; any
+// other refusal is about the client's own request.
+fn backend_failed(e: &io::Error) -> bool {
+    match e.get_ref().and_then(|e| e.downcast_ref::<Refused>()) {
+        Some(refused) => refused.status >= 500,
+        None => true,
+    }
+}
+
+// Tries the candidates in order; a failed backend is marked down until the
+// next successful health probe.
+fn connect(shared: &Shared, peer: &Peer, candidates: &[usize]) -> Option<Client<Upstream>> {
+    let config = upstream_config(peer);
+    let (healthy, down): (Vec<usize>, Vec<usize>) = candidates
+        .iter()
+        .partition(|&&index| shared.pool[index].healthy.load(Ordering::Relaxed));
+    for index in healthy.into_iter().chain(down) {
+        let backend = &shared.pool[index];
+        match Upstream::connect(backend, &peer.request.path, &config) {
+            Ok(client) => {
+                if shared.verbosity >= 2 {
+                    println!("{} -> {}", peer.id, backend.url);
+                }
+                return Some(client);
+            }
+            Err(e) => {
+                if backend_failed(&e) {
+                    backend.healthy.store(false, Ordering::Relaxed);
+                }
+                eprintln!("{}: backend {} failed: {e}", peer.id, backend.url);
+            }
+        }
+    }
+    None
+}
+
+// Writes downstream messages upstream as they arrive. The channel closes
+// with the downstream connection, which sends Going Away.
+fn forward(outbound: Arc<Mutex<Outbound>>, downstream: mpsc::Receiver<Message>) {
+    loop {
+        let message = downstream
+            .recv()
+            .unwrap_or_else(|_| Message::close(1001, "Going Away"));
+        let mask = random::mask();
+        let blob = message.encode(Some(mask));
+
+        let mut outbound = outbound.lock().unwrap();
+        if outbound.write_all(&blob).is_err() {
+            let _ = outbound.stream.shutdown(Shutdown::Both);
+            return;
+        }
+        // The backend gets a while to echo the close before the read fails.
+        if outbound.closed {
+            let _ = outbound.stream.set_read_timeout(Some(CLOSE_TIMEOUT));
+            return;
+        }
+    }
+}
+
+fn relay(
+    server: ServerHandle,
+    id: ConnectionId,
+    mut client: Client<Upstream>,
+    downstream: mpsc::Receiver<Message>,
+) {
+    let outbound = client.stream().outbound.clone();
+    thread::spawn(move || forward(outbound, downstream));
+    loop {
+        match client.recv() {
+            Ok(Message::Close(Some((status, reason)))) => break server.close(id, status, &reason),
+            Ok(Message::Close(None)) => break server.close(id, 1000, ""),
+            Ok(message) => server.send_to(id, message),
+            Err(_) => break server.close(id, 1014, "bad gateway"),
+        }
+    }
+    let _ = client.stream().stream.shutdown(Shutdown::Both);
+}
+
+impl Handler for Proxy {
+    // Unroutable paths are refused before the upgrade, while the client
+    // can still be told with a status code.
+    fn on_handshake(&mut self, _server: &ServerHandle, peer: &mut Peer) -> Result<(), Response> {
+        match self.0.route(&peer.request.path) {
+            Some(_) => Ok(()),
+            None => Err(Response::new(404)),
+        }
+    }
+
+    fn on_open(&mut self, server: &ServerHandle, peer: &Peer) {
+        let shared = &self.0;
+        if shared.verbosity >= 2 {
+            println!("open {} from {} {}", peer.id, peer.addr, peer.request.path);
+        }
+        // Routes are fixed at startup, so on_handshake has already refused
+        // any path this would miss.
+        let Some(route) = shared.route(&peer.request.path) else {
+            return;
+        };
+        let key = shared.affinity.key(&peer.request);
+        let candidates = route.candidates(key, shared.turn.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = mpsc::channel();
+        shared.upstreams.lock().unwrap().insert(peer.id, tx);
+
+        let (shared, server, peer) = (shared.clone(), server.clone(), peer.clone());
+        thread::spawn(move || {
+            match connect(&shared, &peer, &candidates) {
+                Some(client) => relay(server, peer.id, client, rx),
+                None => server.close(peer.id, 1013, "no backend available"),
+            }
+            shared.upstreams.lock().unwrap().remove(&peer.id);
+        });
+    }
+
+    fn on_message(&mut self, _server: &ServerHandle, id: ConnectionId, message: Message) {
+        if let Some(upstream) = self.0.upstreams.lock().unwrap().get(&id) {
+            let _ = upstream.send(message);
+        }
+    }
+
+    fn on_close(&mut self, _server: &ServerHandle, id: ConnectionId) {
+        if self.0.verbosity >= 2 {
+            println!("close {id}");
+        }
+        self.0.upstreams.lock().unwrap().remove(&id);
+    }
+}
+
+struct Options {
+    binds: Vec<String>,
+    pool: Vec<Backend>,
+    routes: Vec<(String, Vec<usize>)>,
+    affinity: Affinity,
+    health_interval: Option<Duration>,
+    verbosity: u8,
+    config: Config,
+}
+
+fn add_backends(pool: &mut Vec<Backend>, urls: &str) -> Result<Vec<usize>, String> {
+    let mut indices = vec![];
+    for url in urls.split(',').map(str::trim).filter(|url| !url.is_empty()) {
+        let backend = Backend::new(url)?;
+        let index = match pool.iter().position(|known| known.url == backend.url) {
+            Some(index) => index,
+            None => {
+                pool.push(backend);
+                pool.len() - 1
+            }
+        };
+        indices.push(index);
+    }
+    Ok(indices)
+}
+
+fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        binds: vec![],
+        pool: vec![],
+        routes: vec![],
+        affinity: Affinity::None,
+        health_interval: Some(Duration::from_secs(5)),
+        verbosity: 1,
+        config: Config::default(),
+    };
+    let mut default = vec![];
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") && flag != "--route" => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg, None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("missing value for {flag}"))
+        };
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-b" | "--bind" => options.binds.push(value()?),
+            "--backend" => default.extend(add_backends(&mut options.pool, &value()?)?),
+            "--route" => {
+                let route = value()?;
+                let (prefix, urls) = route
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid route: {route}"))?;
+                let backends = add_backends(&mut options.pool, urls)?;
+                if !prefix.starts_with('/') || backends.is_empty() {
+                    return Err(format!("invalid route: {route}"));
+                }
+                options.routes.push((prefix.to_string(), backends));
+            }
+            "--hash-header" => options.affinity = Affinity::Header(value()?),
+            "--hash-cookie" => options.affinity = Affinity::Cookie(value()?),
+            "--health-interval" => {
+                let seconds = value()?;
+                let seconds: f64 = seconds
+                    .parse()
+                    .map_err(|_| format!("invalid value for {flag}: {seconds}"))?;
+                options.health_interval = Duration::try_from_secs_f64(seconds)
+                    .ok()
+                    .filter(|every| !every.is_zero());
+            }
+            "-p" | "--protocol" => options.config.protocols.push(value()?),
+            "-v" | "--verbose" => options.verbosity += 1,
+            "-vv" => options.verbosity += 2,
+            "-q" | "--quiet" => options.verbosity = 0,
+            other => return Err(format!("unknown option: {other}")),
+        }
+    }
+    if !default.is_empty() {
+        options.routes.push(("/".to_string(), default));
+    }
+    if options.routes.is_empty() {
+        return Err("no backends configured".to_string());
+    }
+    if options.binds.is_empty() {
+        options.binds.push("127.0.0.1:8080".to_string());
+    }
+    Ok(Some(options))
+}
+
+fn bind(addr: &str) -> Result<Listener, String> {
+    let listener = match addr.strip_prefix("unix:") {
+        Some(path) => UnixSocket::bind(path).map(Listener::from),
+        None => TcpListener::bind(addr).map(Listener::from),
+    };
+    listener.map_err(|e| format!("failed to bind {addr}: {e}"))
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let mut binds = options.binds.iter();
+    let first = bind(binds.next().unwrap())?;
+    let mut server = Server::with_config(first, options.config).map_err(|e| e.to_string())?;
+    for addr in binds {
+        server.add_listener(bind(addr)?);
+    }
+    server
+        .shutdown_on(&[SIGINT, SIGTERM])
+        .map_err(|e| e.to_string())?;
+
+    let routes = options
+        .routes
+        .into_iter()
+        .map(|(prefix, backends)| Route::new(prefix, backends, &options.pool))
+        .collect();
+    let shared = Arc::new(Shared {
+        pool: options.pool,
+        routes,
+        affinity: options.affinity,
+        turn: AtomicUsize::new(0),
+        upstreams: Mutex::new(HashMap::new()),
+        verbosity: options.verbosity,
+    });
+    if let Some(every) = options.health_interval {
+        let shared = shared.clone();
+        thread::spawn(move || loop {
+            for backend in &shared.pool {
+                let was = backend.healthy.load(Ordering::Relaxed);
+                let now = backend.probe();
+                if was != now && shared.verbosity >= 1 {
+                    let state = if now { "up" } else { "down" };
+                    println!("backend {} is {state}", backend.url);
+                }
+            }
+            thread::sleep(every);
+        });
+    }
+    if options.verbosity >= 1 {
+        for listener in server.listeners() {
+            if let Ok(addr) = listener.local_addr() {
+                println!("proxying on {addr}");
+            }
+        }
+    }
+    server.run(Proxy(shared)).map_err(|e| e.to_string())
+}
+
+fn main() {
+    let result = match parse(env::args().skip(1)) {
+        Ok(Some(options)) => run(options),
+        Ok(None) => {
+            println!("{USAGE}");
+            return;
+        }
+        Err(e) => Err(format!("{e}\n\n{USAGE}")),
+    };
+    if let Err(e) = result {
+        eprintln!("proxy: {e}");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    process,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use weso::{
+    limit::Rate,
+    listener::{Listener, UnixSocket},
+    message::Message,
+    server::{Config, ConnectionId, Handler, Peer, Server, ServerHandle},
+    signal::{SIGINT, SIGTERM},
+};
+
+const USAGE: &str = "\
+Usage: server [OPTIONS]
+
+Options:
+  -b, --bind ADDR             listen on ADDR (host:port or unix:PATH); repeatable
+                              [default: 127.0.0.1:3000]
+  -m, --mode MODE             echo, broadcast or log [default: echo]
+      --forward HOST:PORT     bridge each connection to a TCP connection to
+                              HOST:PORT; binary messages carry the bytes and
+                              end of stream closes normally
+      --max-connections N     refuse connections beyond N
+      --max-message-size N    close connections sending messages over N bytes
+      --message-rate N        allow N messages per second per connection
+      --keepalive SECS        ping every SECS seconds, dropping silent peers
+      --allow-origin ORIGIN   only accept browsers from ORIGIN, which may be a
+                              wildcard like https://*.example.com; repeatable
+      --deny-missing-origin   also refuse requests without an Origin header
+  -p, --protocol NAME         accept subprotocol NAME; repeatable, in preference order
+      --reactors N            run N reactor threads [default: 1]
+      --socket-mode MODE      octal permissions for unix sockets [default: 660]
+      --static DIR            serve files from DIR to plain HTTP requests; GET
+                              /healthz always answers ok
+  -v, --verbose               log more; repeat for message contents
+  -q, --quiet                 only log errors
+  -h, --help                  print this help";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mode {
+    Echo,
+    Broadcast,
+    Log,
+    Forward(String),
+}
+
+struct Options {
+    binds: Vec<String>,
+    mode: Mode,
+    verbosity: u8,
+    socket_mode: u32,
+    config: Config,
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {flag}: {value}"))
+}
+
+fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        binds: vec![],
+        mode: Mode::Echo,
+        verbosity: 1,
+        socket_mode: 0o660,
+        config: Config::default(),
+    };
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg, None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("missing value for {flag}"))
+        };
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-b" | "--bind" => options.binds.push(value()?),
+            "-m" | "--mode" => {
+                options.mode = match value()?.as_str() {
+                    "echo" => Mode::Echo,
+                    "broadcast" => Mode::Broadcast,
+                    "log" => Mode::Log,
+                    other => return Err(format!("unknown mode: {other}")),
+                }
+            }
+            "--forward" => options.mode = Mode::Forward(value()?),
+            "--max-connections" => options.config.max_connections = Some(number(&flag, &value()?)?),
+            "--max-message-size" => {
+                options.config.max_message_size = Some(number(&flag, &value()?)?)
+            }
+            "--message-rate" => {
+                let rate: f64 = number(&flag, &value()?)?;
+                options.config.message_rate = Some(Rate::new(rate, rate.max(1.0)));
+            }
+            "--keepalive" => {
+                let seconds: f64 = number(&flag, &value()?)?;
+                options.config.keepalive = Duration::try_from_secs_f64(seconds)
+                    .ok()
+                    .filter(|every| !every.is_zero());
+            }
+            "--allow-origin" => {
+                let origins = options.config.origins.take().unwrap_or_default();
+                options.config.origins = Some(origins.allow(&value()?));
+            }
+            "--deny-missing-origin" => {
+                let origins = options.config.origins.take().unwrap_or_default();
+                options.config.origins = Some(origins.allow_missing(false));
+            }
+            "-p" | "--protocol" => options.config.protocols.push(value()?),
+            "--reactors" => options.config.reactors = number(&flag, &value()?)?,
+            "--socket-mode" => {
+                let mode = value()?;
+                options.socket_mode = u32::from_str_radix(&mode, 8)
+                    .map_err(|_| format!("invalid value for {flag}: {mode}"))?;
+            }
+            "--static" => options.config.http.static_dir = Some(value()?.into()),
+            "-v" | "--verbose" => options.verbosity += 1,
+            "-vv" => options.verbosity += 2,
+            "-q" | "--quiet" => options.verbosity = 0,
+            other => return Err(format!("unknown option: {other}")),
+        }
+    }
+    if options.binds.is_empty() {
+        options.binds.push("127.0.0.1:3000".to_string());
+    }
+    Ok(Some(options))
+}
+
+fn bind(addr: &str, socket_mode: u32) -> Result<Listener, String> {
+    let listener = match addr.strip_prefix("unix:") {
+        Some(path) => UnixSocket::bind_with_mode(path, Some(socket_mode)).map(Listener::from),
+        None => TcpListener::bind(addr).map(Listener::from),
+    };
+    listener.map_err(|e| format!("failed to bind {addr}: {e}"))
+}
+
+fn describe(message: &Message) -> String {
+    match message {
+        Message::Text(text) => format!("text [{}] {text}", text.len()),
+        Message::Binary(data) => format!("binary [{}]", data.len()),
+        Message::Ping(data) => format!("ping [{}]", data.len()),
+        Message::Pong(data) => format!("pong [{}]", data.len()),
+        Message::Close(Some((status, reason))) => format!("close {status} {reason}"),
+        Message::Close(None) => "close".to_string(),
+    }
+}
+
+const CHUNK: usize = 16 * 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Handlers run on the reactor thread, so each upstream is reached and
+// written to by its own thread; the handler only queues payloads.
+type Upstreams = Arc<Mutex<HashMap<ConnectionId, mpsc::Sender<Vec<u8>>>>>;
+
+#[derive(Clone)]
+struct App {
+    mode: Mode,
+    verbosity: u8,
+    upstreams: Upstreams,
+}
+
+// Copies upstream bytes to the client until the upstream hangs up.
+fn relay(server: ServerHandle, id: ConnectionId, mut upstream: TcpStream) {
+    let mut buffer = vec![0u8; CHUNK];
+    loop {
+        match upstream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => server.send_to(id, Message::binary(&buffer[..n])),
+        }
+    }
+    server.close(id, 1000, "");
+}
+
+fn connect(target: &str) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+    for addr in target.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+// Connects, starts the reader and then writes queued payloads until the
+// connection closes and its sender is dropped.
+fn forward(
+    server: ServerHandle,
+    id: ConnectionId,
+    target: String,
+    payloads: mpsc::Receiver<Vec<u8>>,
+) {
+    let upstream = connect(&target).and_then(|stream| {
+        let reader = stream.try_clone()?;
+        Ok((stream, reader))
+    });
+    let (mut upstream, reader) = match upstream {
+        Ok(streams) => streams,
+        Err(e) => {
+            eprintln!("{id}: cannot reach {target}: {e}");
+            return server.close(id, 1011, "upstream unavailable");
+        }
+    };
+    let relaying = server.clone();
+    thread::spawn(move || relay(relaying, id, reader));
+    for payload in payloads {
+        if upstream.write_all(&payload).is_err() {
+            server.close(id, 1011, "upstream write failed");
+            break;
+        }
+    }
+    let _ = upstream.shutdown(Shutdown::Both);
+}
+
+impl Handler for App {
+    fn on_open(&mut self, server: &ServerHandle, peer: &Peer) {
+        if self.verbosity >= 2 {
+            let protocol = peer.protocol.as_deref().unwrap_or("-");
+            println!(
+                "open {} from {} ({}, protocol {protocol})",
+                peer.id, peer.addr, peer.listener
+            );
+        }
+        if let Mode::Forward(target) = &self.mode {
+            let (tx, rx) = mpsc::channel();
+            self.upstreams.lock().unwrap().insert(peer.id, tx);
+            let (server, id, target) = (server.clone(), peer.id, target.clone());
+            thread::spawn(move || forward(server, id, target, rx));
+        }
+    }
+
+    fn on_message(&mut self, server: &ServerHandle, id: ConnectionId, message: Message) {
+        if self.verbosity >= 3 || (self.mode == Mode::Log && self.verbosity >= 1) {
+            println!("{id}: {}", describe(&message));
+        }
+        if message.is_control() {
+            return;
+        }
+        match self.mode {
+            Mode::Echo => server.send_to(id, message),
+            Mode::Broadcast => server.broadcast(message),
+            Mode::Log => {}
+            Mode::Forward(_) => {
+                if let Some(upstream) = self.upstreams.lock().unwrap().get(&id) {
+                    let _ = upstream.send(message.payload().into_owned());
+                }
+            }
+        }
+    }
+
+    fn on_close(&mut self, _server: &ServerHandle, id: ConnectionId) {
+        if self.verbosity >= 2 {
+            println!("close {id}");
+        }
+        self.upstreams.lock().unwrap().remove(&id);
+    }
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let mut binds = options.binds.iter();
+    let first = binds.next().unwrap();
+    let mut server = Server::with_config(bind(first, options.socket_mode)?, options.config)
+        .map_err(|e| e.to_string())?;
+    for addr in binds {
+        server.add_listener(bind(addr, options.socket_mode)?);
+    }
+    server
+        .shutdown_on(&[SIGINT, SIGTERM])
+        .map_err(|e| e.to_string())?;
+    if options.verbosity >= 1 {
+        for listener in server.listeners() {
+            if let Ok(addr) = listener.local_addr() {
+                println!("listening on {addr} ({:?})", options.mode);
+            }
+        }
+    }
+    let app = App {
+        mode: options.mode,
+        verbosity: options.verbosity,
+        upstreams: Upstreams::default(),
+    };
+    server.run(app).map_err(|e| e.to_string())?;
+    if options.verbosity >= 1 {
+        println!("shut down");
+    }
+    Ok(())
+}
+
+fn main() {
+    let result = match parse(env::args().skip(1)) {
+        Ok(Some(options)) => run(options),
+        Ok(None) => {
+            println!("{USAGE}");
+            return;
+        }
+        Err(e) => Err(format!("{e}\n\n{USAGE}")),
+    };
+    if let Err(e) = result {
+        eprintln!("server: {e}");
+        process::exit(1);
+    }
+}
@@ -1,69 +1,474 @@
 use std::{
-    io::{self, BufRead, BufReader, Write},
+    error::Error,
+    fmt,
+    io::{self, ErrorKind, Read, Write},
     net::TcpStream,
+    os::unix::net::UnixStream,
+    path::PathBuf,
 };
 
-use frame::GUID;
-use sha1::Sha1Ctx;
-mod base64;
-mod frame;
-mod sha1;
-mod stream;
-
-pub fn connect(addr: &str) -> io::Result<TcpStream> {
-    let mut stream = TcpStream::connect(addr)?;
-    let dummy_key = "dGhlIHNhbXBsZSBub25jZQ==";
-    //let dummy_mask = [0u8, 1, 2, 3];
-    let req = format!(
-        "GET /chat HTTP/1.1\r\nSec-WebSocket-Key: {}\r\n\r\n",
-        dummy_key
-    );
-    stream.write_all(req.as_bytes())?;
-
-    let mut ctx = Sha1Ctx::new();
-
-    let mut result = String::new();
-    let mut reader = BufReader::new(stream);
-    let mut accepted = false;
-    loop {
-        let n = reader.read_line(&mut result).expect("failed to read line");
-        let splits = result.split(": ").collect::<Vec<&str>>();
-        match splits.get(0) {
-            Some(&"Sec-WebSocket-Accept") => match splits.get(1) {
-                Some(value) => {
-                    if base64::decode(value.trim()) == ctx.digest(&format!("{dummy_key}{}", GUID)) {
-                        accepted = true;
+use crate::{
+    base64,
+    compression::{DeflateConfig, PerMessageDeflate},
+    connection::Incoming,
+    extension::{Chain, Extension, Extensions},
+    frame::{self, apply_mask, Frame},
+    handshake::{accept_key, Limits, Request, Response},
+    message::Message,
+    random,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    pub headers: Vec<(String, String)>,
+    pub protocols: Vec<String>,
+    pub deflate: Option<DeflateConfig>,
+    pub extensions: Extensions,
+}
+
+pub struct Client<S: Read + Write = TcpStream> {
+    stream: S,
+    incoming: Incoming,
+    response: Response,
+    extensions: Chain,
+    close_sent: bool,
+}
+
+// Carried inside the io::Error when the server answers the upgrade with
+// anything but 101, so a refusal can be told apart from a broken socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Refused {
+    pub status: u16,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "upgrade refused with status {}", self.status)
+    }
+}
+
+impl Error for Refused {}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, reason)
+}
+
+// Splits ws://host[:port]/path into a socket address, Host header and path.
+fn split_url(url: &str) -> io::Result<(String, String, String)> {
+    let rest = url
+        .strip_prefix("ws://")
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "unsupported url scheme"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(at) => (&rest[..at], &rest[at..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "missing host"));
+    }
+    let has_port = !authority.ends_with(']')
+        && authority
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    let addr = match has_port {
+        true => authority.to_string(),
+        false => format!("{authority}:80"),
+    };
+    Ok((addr, authority.to_string(), path.to_string()))
+}
+
+// Splits ws+unix://<socket>[:<path>] into the socket path and request path.
+fn split_unix_url(url: &str) -> io::Result<(PathBuf, String)> {
+    let rest = url
+        .strip_prefix("ws+unix://")
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "unsupported url scheme"))?;
+    let (socket, path) = match rest.split_once(':') {
+        Some((socket, path)) if path.starts_with('/') => (socket, path),
+        Some(_) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid request path",
+            ))
+        }
+        None => (rest, "/"),
+    };
+    if socket.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "missing socket path",
+        ));
+    }
+    Ok((PathBuf::from(socket), path.to_string()))
+}
+
+impl Client<TcpStream> {
+    pub fn connect(url: &str) -> io::Result<Self> {
+        Self::connect_with(url, &ClientConfig::default())
+    }
+
+    pub fn connect_with(url: &str, config: &ClientConfig) -> io::Result<Self> {
+        let (addr, host, path) = split_url(url)?;
+        let stream = TcpStream::connect(addr)?;
+        Self::handshake(stream, &host, &path, config)
+    }
+}
+
+impl Client<UnixStream> {
+    pub fn connect_unix(url: &str) -> io::Result<Self> {
+        Self::connect_unix_with(url, &ClientConfig::default())
+    }
+
+    pub fn connect_unix_with(url: &str, config: &ClientConfig) -> io::Result<Self> {
+        let (socket, path) = split_unix_url(url)?;
+        let stream = UnixStream::connect(socket)?;
+        Self::handshake(stream, "localhost", &path, config)
+    }
+}
+
+impl<S: Read + Write> Client<S> {
+    pub fn handshake(
+        mut stream: S,
+        host: &str,
+        path: &str,
+        config: &ClientConfig,
+    ) -> io::Result<Self> {
+        let mut nonce = [0u8; 16];
+        random::fill(&mut nonce);
+        let key = base64::encode(&nonce);
+
+        let mut headers = vec![
+            ("Host".to_string(), host.to_string()),
+            ("Upgrade".to_string(), "websocket".to_string()),
+            ("Connection".to_string(), "Upgrade".to_string()),
+            ("Sec-WebSocket-Key".to_string(), key.clone()),
+            ("Sec-WebSocket-Version".to_string(), "13".to_string()),
+        ];
+        if !config.protocols.is_empty() {
+            headers.push((
+                "Sec-WebSocket-Protocol".to_string(),
+                config.protocols.join(", "),
+            ));
+        }
+        let mut candidates = config.extensions.instantiate();
+        if let Some(deflate) = config.deflate {
+            let deflate: Box<dyn Extension> = Box::new(PerMessageDeflate::new(deflate));
+            candidates.insert(0, deflate);
+        }
+        if let Some(offer) = Chain::offer(&candidates) {
+            headers.push(("Sec-WebSocket-Extensions".to_string(), offer));
+        }
+        headers.extend(config.headers.iter().cloned());
+        let request = Request {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            headers,
+        };
+        stream.write_all(&request.to_bytes())?;
+
+        let mut buffer = vec![];
+        let mut chunk = [0u8; 1024];
+        let (response, used) = loop {
+            match Response::parse(&buffer, &Limits::default()) {
+                Ok(Some(parsed)) => break parsed,
+                Ok(None) => {}
+                Err(_) => return Err(invalid("malformed handshake response")),
+            }
+            match stream.read(&mut chunk)? {
+                0 => return Err(ErrorKind::UnexpectedEof.into()),
+                n => buffer.extend_from_slice(&chunk[..n]),
+            }
+        };
+
+        if response.status != 101 {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                Refused {
+                    status: response.status,
+                },
+            ));
+        }
+        if !response.has_token("Upgrade", "websocket")
+            || !response.has_token("Connection", "upgrade")
+        {
+            return Err(invalid(
+                "response is missing the Upgrade or Connection header",
+            ));
+        }
+        if response.header_value("Sec-WebSocket-Accept") != Some(&accept_key(&key)) {
+            return Err(invalid("invalid Sec-WebSocket-Accept"));
+        }
+        if let Some(protocol) = response.header_value("Sec-WebSocket-Protocol") {
+            if !config.protocols.iter().any(|offered| offered == protocol) {
+                return Err(invalid("server selected an unoffered subprotocol"));
+            }
+        }
+        let extensions = Chain::confirm(
+            candidates,
+            response.header_value("Sec-WebSocket-Extensions"),
+        )
+        .ok_or_else(|| invalid("server selected an unoffered or invalid extension"))?;
+
+        let mut incoming = Incoming::default();
+        incoming.buffer = buffer[used..].to_vec();
+        Ok(Self {
+            stream,
+            incoming,
+            extensions,
+            response,
+            close_sent: false,
+        })
+    }
+
+    pub fn response(&self) -> &Response {
+        &self.response
+    }
+
+    pub fn protocol(&self) -> Option<&str> {
+        self.response.header_value("Sec-WebSocket-Protocol")
+    }
+
+    pub fn extensions(&self) -> Vec<&str> {
+        self.extensions.names()
+    }
+
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    // The payload is copied once, masked in place and written together with
+    // the header in one vectored write.
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        let mask = random::mask();
+        let (rsv, mut payload) = self
+            .extensions
+            .encode(message.opcode(), message.payload().into_owned());
+        let mut header = Frame::new(true, message.opcode(), Some(mask), payload.len());
+        header.rsv = rsv;
+        apply_mask(&mut payload, mask, 0);
+        if let Message::Close(_) = message {
+            self.close_sent = true;
+        }
+        frame::write_frame(&mut self.stream, &header, &payload)
+    }
+
+    pub fn close(&mut self, status: u16, reason: &str) -> io::Result<()> {
+        self.send(&Message::close(status, reason))
+    }
+
+    // Pings are answered and close frames echoed before they are handed back.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match self.incoming.next_message(&mut self.extensions) {
+                Ok(Some(Message::Ping(payload))) => {
+                    self.send(&Message::Pong(payload.clone()))?;
+                    return Ok(Message::Ping(payload));
+                }
+                Ok(Some(Message::Close(payload))) => {
+                    if !self.close_sent {
+                        match &payload {
+                            Some((status, reason)) => self.close(*status, reason)?,
+                            None => self.send(&Message::Close(None))?,
+                        }
                     }
+                    return Ok(Message::Close(payload));
+                }
+                Ok(Some(message)) => return Ok(message),
+                Ok(None) => {}
+                Err(status) => {
+                    let _ = self.close(status, "");
+                    return Err(invalid(&format!("protocol error ({status})")));
                 }
-                None => {}
-            },
-            _ => {}
+            }
+            match self.stream.read(&mut chunk)? {
+                0 => return Err(ErrorKind::UnexpectedEof.into()),
+                n => self.incoming.buffer.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        io::{Read, Write},
+        net::TcpListener,
+        os::unix::net::UnixStream,
+        path::PathBuf,
+        process, thread,
+    };
+
+    use super::{split_unix_url, split_url, Client, ClientConfig, Refused};
+    use crate::{
+        compression::DeflateConfig,
+        frame::{apply_mask, Frame},
+        handshake,
+        message::Message,
+        server::{Config, ConnectionId, Handler, Server, ServerHandle},
+        stream::WsStream,
+    };
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_message(&mut self, server: &ServerHandle, id: ConnectionId, message: Message) {
+            if !message.is_control() {
+                server.send_to(id, message);
+            }
         }
-        if n == 2 {
-            break;
+    }
+
+    #[test]
+    fn urls() {
+        let split = |url| split_url(url).unwrap();
+        assert_eq!(
+            split("ws://example.com/chat?x=1"),
+            (
+                "example.com:80".into(),
+                "example.com".into(),
+                "/chat?x=1".into()
+            )
+        );
+        assert_eq!(
+            split("ws://[::1]:9000"),
+            ("[::1]:9000".into(), "[::1]:9000".into(), "/".into())
+        );
+        assert!(split_url("wss://example.com").is_err());
+
+        let split = |url| split_unix_url(url).unwrap();
+        assert_eq!(
+            split("ws+unix:///run/app.sock"),
+            (PathBuf::from("/run/app.sock"), "/".into())
+        );
+        assert_eq!(
+            split("ws+unix://app.sock:/chat?room=1"),
+            (PathBuf::from("app.sock"), "/chat?room=1".into())
+        );
+        assert!(split_unix_url("ws+unix://app.sock:chat").is_err());
+        assert!(split_unix_url("ws+unix://").is_err());
+    }
+
+    #[test]
+    fn refusal_carries_the_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            stream
+                .write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        });
+        let e = Client::connect(&url).err().unwrap();
+        let refused = e.get_ref().and_then(|e| e.downcast_ref::<Refused>());
+        assert_eq!(refused, Some(&Refused { status: 403 }));
+    }
+
+    #[test]
+    fn deflate_echo() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = Config {
+            deflate: Some(DeflateConfig::default()),
+            ..Config::default()
+        };
+        let server = Server::with_config(listener, config).unwrap();
+        let url = format!("ws://{}/", server.local_addr().unwrap());
+        thread::spawn(move || server.run(Echo));
+
+        let config = ClientConfig {
+            deflate: Some(DeflateConfig {
+                server_no_context_takeover: true,
+                ..DeflateConfig::default()
+            }),
+            ..ClientConfig::default()
+        };
+        let mut client = Client::connect_with(&url, &config).unwrap();
+        assert_eq!(client.extensions(), ["permessage-deflate"]);
+        let accepted = client.response().header_value("Sec-WebSocket-Extensions");
+        assert!(accepted.unwrap().contains("server_no_context_takeover"));
+        let json = r#"{"event":"tick","values":[1,2,3]}"#.repeat(100);
+        for _ in 0..3 {
+            client.send(&Message::text(&json)).unwrap();
+            assert_eq!(client.recv().unwrap(), Message::text(&json));
         }
-        result.clear();
+        client.send(&Message::binary(b"")).unwrap();
+        assert_eq!(client.recv().unwrap(), Message::binary(b""));
+
+        let mut plain = Client::connect(&url).unwrap();
+        assert!(plain.extensions().is_empty());
+        plain.send(&Message::text("hi")).unwrap();
+        assert_eq!(plain.recv().unwrap(), Message::text("hi"));
+        plain.close(1000, "").unwrap();
+        assert_eq!(plain.recv().unwrap(), Message::close(1000, ""));
     }
 
-    match accepted {
-        true => Ok(reader.into_inner()),
-        false => Err(io::ErrorKind::ConnectionAborted.into()),
+    #[test]
+    fn handshake_over_any_stream() {
+        let (local, mut remote) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            handshake::upgrade(&mut remote).unwrap();
+            let frame = Frame::read_frame(&mut remote).unwrap();
+            let mut payload = vec![0u8; frame.payload_length];
+            remote.read_exact(&mut payload).unwrap();
+            apply_mask(&mut payload, frame.mask.unwrap(), 0);
+            let text = String::from_utf8(payload).unwrap();
+            WsStream::new(remote)
+                .text(&text.to_uppercase(), None)
+                .unwrap();
+        });
+
+        let config = ClientConfig::default();
+        let mut client = Client::handshake(local, "localhost", "/", &config).unwrap();
+        client.send(&Message::text("over a pipe")).unwrap();
+        assert_eq!(client.recv().unwrap(), Message::text("OVER A PIPE"));
+        server.join().unwrap();
     }
-}
 
-fn main() {
-    // println!("=============client==========");
-// 
-    // let stream = connect("127.0.0.1:3000").unwrap();
-// 
-    // let _ = WsStream::new(stream)
-    //     .fin()
-    //     .text()
-    //     .mask(Some([0, 0, 0, 0]))
-    //     .with_len(11)
-    //     .write_all("hello world".as_bytes());
-// 
-    // println!("=== wrote ===");
-// 
-    // sleep(Duration::from_secs(5));
+    #[test]
+    fn unix_socket_echo() {
+        let path = env::temp_dir().join(format!("weso-client-{}.sock", process::id()));
+        let server = Server::bind_unix(&path).unwrap();
+        let handle = server.handle();
+        let running = thread::spawn(move || server.run(Echo));
+
+        let url = format!("ws+unix://{}:/chat", path.display());
+        let mut client = Client::connect_unix(&url).unwrap();
+        client.send(&Message::text("local")).unwrap();
+        assert_eq!(client.recv().unwrap(), Message::text("local"));
+        assert_eq!(handle.connection_count(), 1);
+
+        handle.shutdown();
+        assert_eq!(client.recv().unwrap(), Message::close(1001, "Going Away"));
+        running.join().unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn response_must_confirm_the_upgrade() {
+        for (upgrade, connection, ok) in [
+            ("WebSocket", "keep-alive, Upgrade", true),
+            ("h2c", "Upgrade", false),
+            ("websocket", "keep-alive", false),
+        ] {
+            let (local, mut remote) = UnixStream::pair().unwrap();
+            let server = thread::spawn(move || {
+                let key = handshake::get_key(&mut remote).unwrap().unwrap();
+                let response = handshake::Response::new(101)
+                    .header("Upgrade", upgrade)
+                    .header("Connection", connection)
+                    .header("Sec-WebSocket-Accept", &handshake::accept_key(&key));
+                remote.write_all(&response.to_bytes()).unwrap();
+            });
+            let config = ClientConfig::default();
+            let result = Client::handshake(local, "localhost", "/", &config);
+            assert_eq!(result.is_ok(), ok);
+            server.join().unwrap();
+        }
+    }
 }
@@ -0,0 +1,355 @@
+use crate::{
+    deflate::{Deflater, InflateError, Inflater, MAX_WINDOW_BITS, MIN_WINDOW_BITS},
+    extension::Extension,
+    frame::{Opcode, RSV1},
+};
+
+pub const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateConfig {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: u8,
+    pub client_max_window_bits: u8,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        Self {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: MAX_WINDOW_BITS,
+            client_max_window_bits: MAX_WINDOW_BITS,
+        }
+    }
+}
+
+struct Params {
+    config: DeflateConfig,
+    server_bits: bool,
+    client_bits: bool,
+}
+
+fn window_bits(value: Option<&str>) -> Option<u8> {
+    value
+        .and_then(|value| value.parse().ok())
+        .filter(|bits| (MIN_WINDOW_BITS..=MAX_WINDOW_BITS).contains(bits))
+}
+
+// Parses one comma-separated element of Sec-WebSocket-Extensions, returning
+// None for other extensions and for malformed or duplicated parameters.
+fn parse(element: &str) -> Option<Params> {
+    let mut parts = element.split(';').map(str::trim);
+    if parts.next()? != PERMESSAGE_DEFLATE {
+        return None;
+    }
+    let mut params = Params {
+        config: DeflateConfig::default(),
+        server_bits: false,
+        client_bits: false,
+    };
+    let mut seen = vec![];
+    for part in parts {
+        let (name, value) = match part.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (part, None),
+        };
+        if seen.contains(&name) {
+            return None;
+        }
+        seen.push(name);
+        match (name, value) {
+            ("server_no_context_takeover", None) => params.config.server_no_context_takeover = true,
+            ("client_no_context_takeover", None) => params.config.client_no_context_takeover = true,
+            ("server_max_window_bits", value) => {
+                params.config.server_max_window_bits = window_bits(value)?;
+                params.server_bits = true;
+            }
+            ("client_max_window_bits", None) => params.client_bits = true,
+            ("client_max_window_bits", value) => {
+                params.config.client_max_window_bits = window_bits(value)?;
+                params.client_bits = true;
+            }
+            _ => return None,
+        }
+    }
+    Some(params)
+}
+
+impl DeflateConfig {
+    fn header(&self, server_bits: bool, client_bits: bool) -> String {
+        let mut header = PERMESSAGE_DEFLATE.to_string();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        if server_bits {
+            header.push_str(&format!(
+                "; server_max_window_bits={}",
+                self.server_max_window_bits
+            ));
+        }
+        if client_bits {
+            header.push_str(&format!(
+                "; client_max_window_bits={}",
+                self.client_max_window_bits
+            ));
+        }
+        header
+    }
+
+    // The client always advertises client_max_window_bits so the server is
+    // free to shrink our window.
+    pub fn offer(&self) -> String {
+        let mut header = self.header(self.server_max_window_bits < MAX_WINDOW_BITS, false);
+        match self.client_max_window_bits {
+            MAX_WINDOW_BITS => header.push_str("; client_max_window_bits"),
+            bits => header.push_str(&format!("; client_max_window_bits={bits}")),
+        }
+        header
+    }
+
+    // Server side: picks the first acceptable offer and returns the agreed
+    // parameters along with the response header value.
+    pub fn accept(&self, header: &str) -> Option<(DeflateConfig, String)> {
+        let offer = header.split(',').find_map(parse)?;
+        let client_max_window_bits = match offer.client_bits {
+            true => offer
+                .config
+                .client_max_window_bits
+                .min(self.client_max_window_bits),
+            false => MAX_WINDOW_BITS,
+        };
+        let agreed = DeflateConfig {
+            server_no_context_takeover: offer.config.server_no_context_takeover
+                || self.server_no_context_takeover,
+            client_no_context_takeover: offer.config.client_no_context_takeover
+                || self.client_no_context_takeover,
+            server_max_window_bits: offer
+                .config
+                .server_max_window_bits
+                .min(self.server_max_window_bits),
+            client_max_window_bits,
+        };
+        let header = agreed.header(
+            offer.server_bits || agreed.server_max_window_bits < MAX_WINDOW_BITS,
+            offer.client_bits && agreed.client_max_window_bits < MAX_WINDOW_BITS,
+        );
+        Some((agreed, header))
+    }
+
+    // Client side: validates the server's answer against what we offered.
+    pub fn confirm(&self, header: &str) -> Option<DeflateConfig> {
+        let mut elements = header.split(',');
+        let response = parse(elements.next()?)?;
+        if elements.next().is_some()
+            || (self.server_no_context_takeover && !response.config.server_no_context_takeover)
+            || response.config.server_max_window_bits > self.server_max_window_bits
+            || response.config.client_max_window_bits > self.client_max_window_bits
+        {
+            return None;
+        }
+        Some(DeflateConfig {
+            client_no_context_takeover: response.config.client_no_context_takeover
+                || self.client_no_context_takeover,
+            client_max_window_bits: match response.client_bits {
+                true => response.config.client_max_window_bits,
+                false => self.client_max_window_bits,
+            },
+            ..response.config
+        })
+    }
+}
+
+pub struct PerMessageDeflate {
+    config: DeflateConfig,
+    agreed: Option<DeflateConfig>,
+    deflater: Deflater,
+    inflater: Inflater,
+}
+
+impl PerMessageDeflate {
+    pub fn new(config: DeflateConfig) -> Self {
+        Self {
+            config,
+            agreed: None,
+            deflater: Deflater::new(MAX_WINDOW_BITS, true),
+            inflater: Inflater::new(true),
+        }
+    }
+
+    pub fn server(agreed: &DeflateConfig) -> Self {
+        let mut deflate = Self::new(*agreed);
+        deflate.agree(*agreed, true);
+        deflate
+    }
+
+    pub fn client(agreed: &DeflateConfig) -> Self {
+        let mut deflate = Self::new(*agreed);
+        deflate.agree(*agreed, false);
+        deflate
+    }
+
+    fn agree(&mut self, agreed: DeflateConfig, server: bool) {
+        let (bits, no_takeover, peer_no_takeover) = match server {
+            true => (
+                agreed.server_max_window_bits,
+                agreed.server_no_context_takeover,
+                agreed.client_no_context_takeover,
+            ),
+            false => (
+                agreed.client_max_window_bits,
+                agreed.client_no_context_takeover,
+                agreed.server_no_context_takeover,
+            ),
+        };
+        self.deflater = Deflater::new(bits, !no_takeover);
+        self.inflater = Inflater::new(!peer_no_takeover);
+        self.agreed = Some(agreed);
+    }
+
+    pub fn agreed(&self) -> Option<&DeflateConfig> {
+        self.agreed.as_ref()
+    }
+
+    pub fn compress(&mut self, payload: &[u8]) -> Vec<u8> {
+        let mut compressed = self.deflater.compress(payload);
+        compressed.truncate(compressed.len() - TRAILER.len());
+        compressed
+    }
+
+    pub fn decompress(&mut self, payload: &[u8], max: usize) -> Result<Vec<u8>, InflateError> {
+        let mut input = Vec::with_capacity(payload.len() + TRAILER.len());
+        input.extend_from_slice(payload);
+        input.extend_from_slice(&TRAILER);
+        self.inflater.decompress(&input, max)
+    }
+}
+
+impl Extension for PerMessageDeflate {
+    fn name(&self) -> &str {
+        PERMESSAGE_DEFLATE
+    }
+
+    fn rsv(&self) -> u8 {
+        RSV1
+    }
+
+    fn offer(&self) -> String {
+        self.config.offer()
+    }
+
+    fn accept(&mut self, offer: &str) -> Option<String> {
+        let (agreed, header) = self.config.accept(offer)?;
+        self.agree(agreed, true);
+        Some(header)
+    }
+
+    fn confirm(&mut self, response: &str) -> bool {
+        match self.config.confirm(response) {
+            Some(agreed) => {
+                self.agree(agreed, false);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn encode(&mut self, opcode: Opcode, rsv: &mut u8, payload: Vec<u8>) -> Vec<u8> {
+        if opcode.is_control() {
+            return payload;
+        }
+        *rsv |= RSV1;
+        self.compress(&payload)
+    }
+
+    fn decode(
+        &mut self,
+        opcode: Opcode,
+        rsv: u8,
+        payload: Vec<u8>,
+        max: usize,
+    ) -> Result<Vec<u8>, u16> {
+        match (rsv & RSV1 != 0, opcode.is_control()) {
+            (false, _) => Ok(payload),
+            (true, true) => Err(1002),
+            (true, false) => match self.decompress(&payload, max) {
+                Ok(payload) => Ok(payload),
+                Err(InflateError::OutputLimit) => Err(1009),
+                Err(_) => Err(1007),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeflateConfig, PerMessageDeflate};
+    use crate::{
+        extension::Extension,
+        frame::{Opcode, RSV1},
+    };
+
+    #[test]
+    fn negotiate() {
+        let server = DeflateConfig {
+            server_max_window_bits: 12,
+            ..DeflateConfig::default()
+        };
+        let client = DeflateConfig {
+            client_no_context_takeover: true,
+            ..DeflateConfig::default()
+        };
+
+        let offer = client.offer();
+        assert_eq!(
+            offer,
+            "permessage-deflate; client_no_context_takeover; client_max_window_bits"
+        );
+        let (agreed, header) = server.accept(&format!("x-webkit, {offer}")).unwrap();
+        assert_eq!(
+            header,
+            "permessage-deflate; client_no_context_takeover; server_max_window_bits=12"
+        );
+        assert_eq!(client.confirm(&header), Some(agreed));
+        assert!(agreed.client_no_context_takeover && !agreed.server_no_context_takeover);
+
+        assert!(server
+            .accept("permessage-deflate; server_max_window_bits")
+            .is_none());
+        assert!(server
+            .accept("permessage-deflate; client_max_window_bits=7")
+            .is_none());
+        assert!(server
+            .accept("permessage-deflate; server_no_context_takeover; server_no_context_takeover")
+            .is_none());
+        assert!(server
+            .accept("permessage-deflate; foo, permessage-deflate")
+            .is_some());
+        assert!(client
+            .confirm("permessage-deflate; client_max_window_bits=15, x-foo")
+            .is_none());
+
+        let mut sender = PerMessageDeflate::server(&agreed);
+        let mut receiver = PerMessageDeflate::client(&agreed);
+        for _ in 0..3 {
+            let compressed = sender.compress(b"Hello");
+            assert!(!compressed.ends_with(&[0x00, 0x00, 0xff, 0xff]));
+            assert_eq!(
+                receiver.decompress(&compressed, usize::MAX).unwrap(),
+                b"Hello"
+            );
+        }
+
+        let bomb = sender.compress(&[b'a'; 100_000]);
+        assert_eq!(
+            receiver.decode(Opcode::Binary, RSV1, bomb, 99_999),
+            Err(1009)
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, IoSlice, Read, Write},
+    sync::Arc,
+};
+
+use crate::{
+    extension::Chain,
+    frame::{apply_mask, Frame, Opcode, MAX_HEADER_SIZE},
+    limit::TokenBucket,
+    listener::Address,
+    message::Message,
+    server::ConnectionId,
+    stream::Transport,
+    timer::TimerId,
+};
+
+// The most frames gathered into one write; Linux's IOV_MAX is 1024.
+const MAX_IOVECS: usize = 64;
+
+// The most 4K reads taken per readiness event, so a peer that keeps
+// sending cannot hold the reactor; poll reports the rest next time.
+const MAX_READS: usize = 4;
+
+pub(crate) struct Outbound {
+    blob: Arc<[u8]>,
+    written: usize,
+}
+
+// The message size limit when none is configured, so a declared length
+// can never make the reassembly buffer grow without bound.
+pub(crate) const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+// Reassembles frames read off the wire into messages; shared by server
+// connections and the blocking client.
+#[derive(Default)]
+pub(crate) struct Incoming {
+    pub buffer: Vec<u8>,
+    pub max_message_size: Option<usize>,
+    fragments: Option<(Opcode, u8, Vec<u8>)>,
+}
+
+pub(crate) struct Connection {
+    pub id: ConnectionId,
+    pub addr: Address,
+    pub stream: Box<dyn Transport>,
+    pub incoming: Incoming,
+    pub extensions: Chain,
+    pub outbound: VecDeque<Outbound>,
+    pub close_sent: bool,
+    pub close_received: bool,
+    pub close_timer: Option<TimerId>,
+    pub bucket: Option<TokenBucket>,
+    pub awaiting_pong: bool,
+}
+
+impl Connection {
+    pub fn new(id: ConnectionId, addr: Address, stream: Box<dyn Transport>) -> Self {
+        Self {
+            id,
+            addr,
+            stream,
+            incoming: Incoming::default(),
+            extensions: Chain::default(),
+            outbound: VecDeque::new(),
+            close_sent: false,
+            close_received: false,
+            close_timer: None,
+            bucket: None,
+            awaiting_pong: false,
+        }
+    }
+
+    pub fn enqueue(&mut self, blob: Arc<[u8]>) {
+        if !self.close_sent {
+            self.outbound.push_back(Outbound { blob, written: 0 });
+        }
+    }
+
+    pub fn enqueue_close(&mut self, status: u16, reason: &str) {
+        self.enqueue(Message::close(status, reason).encode(None).into());
+        self.close_sent = true;
+    }
+
+    // Hands the kernel as many queued frames as fit in one vectored write.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.outbound.is_empty() {
+            let mut slices = [IoSlice::new(&[]); MAX_IOVECS];
+            let mut count = 0;
+            for (slice, outbound) in slices.iter_mut().zip(&self.outbound) {
+                *slice = IoSlice::new(&outbound.blob[outbound.written..]);
+                count += 1;
+            }
+            match self.stream.write_vectored(&slices[..count]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(mut n) => {
+                    while let Some(front) = self.outbound.front_mut() {
+                        let left = front.blob.len() - front.written;
+                        if n < left {
+                            front.written += n;
+                            break;
+                        }
+                        n -= left;
+                        self.outbound.pop_front();
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn fill(&mut self) -> io::Result<bool> {
+        let mut buffer = [0u8; 4096];
+        for _ in 0..MAX_READS {
+            if self.incoming.over_limit() {
+                break;
+            }
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.incoming.buffer.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn next_message(&mut self) -> Result<Option<Message>, u16> {
+        self.incoming.next_message(&mut self.extensions)
+    }
+
+    // Messages are queued already encoded and shared between connections,
+    // so running them through extensions means re-framing the payload.
+    pub fn send(&mut self, blob: Arc<[u8]>) {
+        if self.extensions.is_empty() {
+            return self.enqueue(blob);
+        }
+        match Frame::parse(&blob) {
+            Ok(Some((frame, header))) if frame.is_final => {
+                let (rsv, payload) = self
+                    .extensions
+                    .encode(frame.opcode, blob[header..].to_vec());
+                let mut encoded = Frame::new(true, frame.opcode, None, payload.len());
+                encoded.rsv = rsv;
+                let header = encoded.header();
+                let mut blob = Vec::with_capacity(header.len() + payload.len());
+                blob.extend_from_slice(&header);
+                blob.extend_from_slice(&payload);
+                self.enqueue(blob.into());
+            }
+            _ => self.enqueue(blob),
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        self.close_sent && self.close_received && self.outbound.is_empty()
+    }
+}
+
+impl Incoming {
+    fn max(&self) -> usize {
+        self.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    // More buffered than one frame of the largest message can take.
+    fn over_limit(&self) -> bool {
+        self.buffer.len() > self.max().saturating_add(MAX_HEADER_SIZE)
+    }
+
+    // Waiting for the rest of a frame is only fine while it can still fit.
+    fn incomplete(&self) -> Result<Option<Message>, u16> {
+        if self.over_limit() {
+            Err(1009)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn next_message(&mut self, extensions: &mut Chain) -> Result<Option<Message>, u16> {
+        loop {
+            let (frame, header) = match Frame::parse(&self.buffer)? {
+                Some(parsed) => parsed,
+                None => return self.incomplete(),
+            };
+            let buffered = self.fragments.as_ref().map_or(0, |(_, _, data)| data.len());
+            let max = self.max();
+            if buffered
+                .checked_add(frame.payload_length)
+                .is_none_or(|total| total > max)
+            {
+                return Err(1009);
+            }
+            let Some(end) = header.checked_add(frame.payload_length) else {
+                return Err(1009);
+            };
+            if self.buffer.len() < end {
+                return self.incomplete();
+            }
+            let mut payload: Vec<u8> = self.buffer.drain(..end).skip(header).collect();
+            if let Some(mask) = frame.mask {
+                apply_mask(&mut payload, mask, 0);
+            }
+
+            if frame.rsv & !extensions.rsv() != 0 {
+                return Err(1002);
+            }
+
+            let (opcode, rsv, payload) = match frame.opcode {
+                Opcode::Reserved(_) => return Err(1002),
+                Opcode::Close | Opcode::Ping | Opcode::Pong => {
+                    if !frame.is_final || payload.len() > 125 {
+                        return Err(1002);
+                    }
+                    (frame.opcode, frame.rsv, payload)
+                }
+                Opcode::Text | Opcode::Binary => {
+                    if self.fragments.is_some() {
+                        return Err(1002);
+                    }
+                    if !frame.is_final {
+                        self.fragments = Some((frame.opcode, frame.rsv, payload));
+                        continue;
+                    }
+                    (frame.opcode, frame.rsv, payload)
+                }
+                Opcode::Continuation => match self.fragments.as_mut() {
+                    _ if frame.rsv != 0 => return Err(1002),
+                    None => return Err(1002),
+                    Some((_, _, buffer)) => {
+                        buffer.extend_from_slice(&payload);
+                        if !frame.is_final {
+                            continue;
+                        }
+                        self.fragments.take().unwrap()
+                    }
+                },
+            };
+            let payload = extensions.decode(opcode, rsv, payload, max)?;
+            if payload.len() > max {
+                return Err(1009);
+            }
+
+            return match Message::from_parts(opcode, payload) {
+                Some(message) => Ok(Some(message)),
+                None if opcode == Opcode::Text => Err(1007),
+                None => Err(1002),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Connection, Incoming};
+    use crate::{extension::Chain, listener::Address, server::ConnectionId};
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    fn frame(length: u64) -> Vec<u8> {
+        let mut blob = vec![0x82, 0xff];
+        blob.extend_from_slice(&length.to_be_bytes());
+        blob.extend_from_slice(&[1, 2, 3, 4]);
+        blob
+    }
+
+    #[test]
+    fn declared_lengths_are_capped() {
+        let mut chain = Chain::default();
+        for (length, code) in [(u64::MAX, 1002), (i64::MAX as u64, 1009), (1 << 40, 1009)] {
+            let mut incoming = Incoming {
+                buffer: frame(length),
+                ..Incoming::default()
+            };
+            assert_eq!(incoming.next_message(&mut chain), Err(code));
+        }
+
+        let mut incoming = Incoming {
+            buffer: frame(1 << 20),
+            max_message_size: Some(usize::MAX),
+            ..Incoming::default()
+        };
+        assert_eq!(incoming.next_message(&mut chain), Ok(None));
+    }
+
+    #[test]
+    fn fill_is_bounded() {
+        let (local, mut remote) = UnixStream::pair().unwrap();
+        local.set_nonblocking(true).unwrap();
+        remote.write_all(&[0x82; 64 << 10]).unwrap();
+        let mut conn = Connection::new(ConnectionId(1), Address::Unix(None), Box::new(local));
+        assert!(conn.fill().unwrap());
+        assert_eq!(conn.incoming.buffer.len(), 16 << 10);
+
+        conn.incoming.buffer.clear();
+        conn.incoming.max_message_size = Some(100);
+        assert!(conn.fill().unwrap());
+        assert_eq!(conn.incoming.buffer.len(), 4 << 10);
+    }
+}
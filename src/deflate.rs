@@ -0,0 +1,825 @@
+use std::{cmp::Reverse, collections::BinaryHeap, error::Error, fmt};
+
+pub const MAX_WINDOW_BITS: u8 = 15;
+pub const MIN_WINDOW_BITS: u8 = 8;
+
+const MAX_WINDOW: usize = 1 << MAX_WINDOW_BITS;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+const BLOCK_TOKENS: usize = 1 << 14;
+const SYNC_FLUSH: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const CODE_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InflateError {
+    UnexpectedEof,
+    InvalidBlockType,
+    InvalidStoredLength,
+    InvalidCode,
+    InvalidDistance,
+    OutputLimit,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::UnexpectedEof => "unexpected end of deflate stream",
+            Self::InvalidBlockType => "invalid deflate block type",
+            Self::InvalidStoredLength => "stored block length mismatch",
+            Self::InvalidCode => "invalid huffman code",
+            Self::InvalidDistance => "distance too far back",
+            Self::OutputLimit => "inflated output over the limit",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl Error for InflateError {}
+
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let mut literals = vec![8u8; 288];
+    literals[144..256].fill(9);
+    literals[256..280].fill(7);
+    (literals, vec![5u8; 30])
+}
+
+fn length_symbol(length: usize) -> usize {
+    (0..LENGTH_BASE.len())
+        .rev()
+        .find(|&i| LENGTH_BASE[i] as usize <= length)
+        .unwrap()
+}
+
+fn distance_symbol(distance: usize) -> usize {
+    (0..DIST_BASE.len())
+        .rev()
+        .find(|&i| DIST_BASE[i] as usize <= distance)
+        .unwrap()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Match(u16, u16),
+}
+
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        Self {
+            out,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, value: u32, count: u8) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += count as u32;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are packed starting from their most significant bit.
+    fn code(&mut self, code: u16, length: u8) {
+        let reversed = code.reverse_bits() >> (16 - length as u32);
+        self.bits(reversed as u32, length);
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.bits(0, (8 - self.count) as u8);
+        }
+    }
+}
+
+fn huffman_lengths(freqs: &[u32]) -> Vec<u8> {
+    let mut lengths = vec![0u8; freqs.len()];
+    let used: Vec<usize> = (0..freqs.len()).filter(|&i| freqs[i] > 0).collect();
+    match used.len() {
+        0 => return lengths,
+        1 => {
+            lengths[used[0]] = 1;
+            return lengths;
+        }
+        _ => {}
+    }
+
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = used
+        .iter()
+        .enumerate()
+        .map(|(node, &symbol)| Reverse((freqs[symbol] as u64, node)))
+        .collect();
+    let mut parent = vec![0usize; used.len() * 2 - 1];
+    let mut next = used.len();
+    while heap.len() > 1 {
+        let Reverse((a, x)) = heap.pop().unwrap();
+        let Reverse((b, y)) = heap.pop().unwrap();
+        parent[x] = next;
+        parent[y] = next;
+        heap.push(Reverse((a + b, next)));
+        next += 1;
+    }
+
+    // Parents are always created after their children, so one pass from the
+    // root downwards settles every depth.
+    let mut depth = vec![0usize; next];
+    for node in (0..next - 1).rev() {
+        depth[node] = depth[parent[node]] + 1;
+    }
+    for (node, &symbol) in used.iter().enumerate() {
+        lengths[symbol] = u8::try_from(depth[node]).unwrap_or(u8::MAX);
+    }
+    lengths
+}
+
+// Flattening the frequencies until the tree fits is not optimal, but it
+// always terminates and rarely kicks in at all.
+fn limited_lengths(freqs: &[u32], limit: u8) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    loop {
+        let lengths = huffman_lengths(&freqs);
+        if lengths.iter().all(|&length| length <= limit) {
+            return lengths;
+        }
+        for freq in freqs.iter_mut().filter(|freq| **freq > 0) {
+            *freq = (*freq >> 1) | 1;
+        }
+    }
+}
+
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; 16];
+    for &length in lengths {
+        counts[length as usize] += 1;
+    }
+    counts[0] = 0;
+    let mut next = [0u16; 16];
+    let mut code = 0u16;
+    for bits in 1..16 {
+        code = (code + counts[bits - 1]) << 1;
+        next[bits] = code;
+    }
+    lengths
+        .iter()
+        .map(|&length| {
+            if length == 0 {
+                return 0;
+            }
+            let code = next[length as usize];
+            next[length as usize] += 1;
+            code
+        })
+        .collect()
+}
+
+// Run-length encodes code lengths with the 16/17/18 repeat symbols, returning
+// (symbol, extra bits value) pairs.
+fn run_lengths(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut runs = vec![];
+    let mut i = 0;
+    while i < lengths.len() {
+        let length = lengths[i];
+        let run = lengths[i..].iter().take_while(|&&l| l == length).count();
+        if length == 0 && run >= 3 {
+            let run = run.min(138);
+            if run >= 11 {
+                runs.push((18, (run - 11) as u8));
+            } else {
+                runs.push((17, (run - 3) as u8));
+            }
+            i += run;
+        } else if length != 0 && run >= 4 {
+            let repeat = (run - 1).min(6);
+            runs.push((length, 0));
+            runs.push((16, (repeat - 3) as u8));
+            i += 1 + repeat;
+        } else {
+            runs.push((length, 0));
+            i += 1;
+        }
+    }
+    runs
+}
+
+fn repeat_extra(symbol: u8) -> u8 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+struct Dynamic {
+    literals: Vec<u8>,
+    distances: Vec<u8>,
+    codes: Vec<u8>,
+    runs: Vec<(u8, u8)>,
+    hlit: usize,
+    hdist: usize,
+    hclen: usize,
+}
+
+impl Dynamic {
+    fn new(literal_freqs: &[u32], distance_freqs: &[u32]) -> Self {
+        let literals = limited_lengths(literal_freqs, 15);
+        let mut distances = limited_lengths(distance_freqs, 15);
+        if distances.iter().all(|&length| length == 0) {
+            distances[0] = 1;
+        }
+        let hlit = 257.max(literals.iter().rposition(|&l| l > 0).unwrap_or(0) + 1);
+        let hdist = 1.max(distances.iter().rposition(|&l| l > 0).unwrap_or(0) + 1);
+
+        let mut all = literals[..hlit].to_vec();
+        all.extend_from_slice(&distances[..hdist]);
+        let runs = run_lengths(&all);
+        let mut code_freqs = [0u32; 19];
+        for &(symbol, _) in &runs {
+            code_freqs[symbol as usize] += 1;
+        }
+        let codes = limited_lengths(&code_freqs, 7);
+        let hclen = 4.max(
+            CODE_ORDER
+                .iter()
+                .rposition(|&symbol| codes[symbol] > 0)
+                .unwrap_or(0)
+                + 1,
+        );
+        Self {
+            literals,
+            distances,
+            codes,
+            runs,
+            hlit,
+            hdist,
+            hclen,
+        }
+    }
+
+    fn header_cost(&self) -> usize {
+        let runs: usize = self
+            .runs
+            .iter()
+            .map(|&(symbol, _)| {
+                self.codes[symbol as usize] as usize + repeat_extra(symbol) as usize
+            })
+            .sum();
+        5 + 5 + 4 + 3 * self.hclen + runs
+    }
+
+    fn write_header(&self, writer: &mut BitWriter) {
+        writer.bits((self.hlit - 257) as u32, 5);
+        writer.bits((self.hdist - 1) as u32, 5);
+        writer.bits((self.hclen - 4) as u32, 4);
+        for &symbol in &CODE_ORDER[..self.hclen] {
+            writer.bits(self.codes[symbol] as u32, 3);
+        }
+        let codes = canonical_codes(&self.codes);
+        for &(symbol, extra) in &self.runs {
+            writer.code(codes[symbol as usize], self.codes[symbol as usize]);
+            let bits = repeat_extra(symbol);
+            if bits > 0 {
+                writer.bits(extra as u32, bits);
+            }
+        }
+    }
+}
+
+fn cost(freqs: &[u32], lengths: &[u8]) -> usize {
+    freqs
+        .iter()
+        .zip(lengths)
+        .map(|(&freq, &length)| freq as usize * length as usize)
+        .sum()
+}
+
+fn write_tokens(writer: &mut BitWriter, tokens: &[Token], literals: &[u8], distances: &[u8]) {
+    let literal_codes = canonical_codes(literals);
+    let distance_codes = canonical_codes(distances);
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => {
+                writer.code(literal_codes[byte as usize], literals[byte as usize]);
+            }
+            Token::Match(length, distance) => {
+                let symbol = length_symbol(length as usize);
+                writer.code(literal_codes[257 + symbol], literals[257 + symbol]);
+                writer.bits((length - LENGTH_BASE[symbol]) as u32, LENGTH_EXTRA[symbol]);
+                let symbol = distance_symbol(distance as usize);
+                writer.code(distance_codes[symbol], distances[symbol]);
+                writer.bits((distance - DIST_BASE[symbol]) as u32, DIST_EXTRA[symbol]);
+            }
+        }
+    }
+    writer.code(literal_codes[256], literals[256]);
+}
+
+// Emits one non-final block, choosing whichever of stored, fixed or dynamic
+// Huffman coding comes out smallest.
+fn write_block(writer: &mut BitWriter, tokens: &[Token], raw: &[u8]) {
+    let mut literal_freqs = [0u32; 286];
+    let mut distance_freqs = [0u32; 30];
+    let mut extra = 0usize;
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => literal_freqs[byte as usize] += 1,
+            Token::Match(length, distance) => {
+                let symbol = length_symbol(length as usize);
+                literal_freqs[257 + symbol] += 1;
+                extra += LENGTH_EXTRA[symbol] as usize;
+                let symbol = distance_symbol(distance as usize);
+                distance_freqs[symbol] += 1;
+                extra += DIST_EXTRA[symbol] as usize;
+            }
+        }
+    }
+    literal_freqs[256] = 1;
+
+    let (fixed_literals, fixed_distances) = fixed_lengths();
+    let fixed = cost(&literal_freqs, &fixed_literals) + cost(&distance_freqs, &fixed_distances);
+    let dynamic = Dynamic::new(&literal_freqs, &distance_freqs);
+    let dynamic_cost = dynamic.header_cost()
+        + cost(&literal_freqs, &dynamic.literals)
+        + cost(&distance_freqs, &dynamic.distances);
+    let stored = 7 + 32 + raw.len() * 8;
+
+    if raw.len() <= u16::MAX as usize && stored <= fixed.min(dynamic_cost) + extra {
+        writer.bits(0b000, 3);
+        writer.align();
+        let length = raw.len() as u16;
+        writer.out.extend_from_slice(&length.to_le_bytes());
+        writer.out.extend_from_slice(&(!length).to_le_bytes());
+        writer.out.extend_from_slice(raw);
+    } else if fixed <= dynamic_cost {
+        writer.bits(0b010, 3);
+        write_tokens(writer, tokens, &fixed_literals, &fixed_distances);
+    } else {
+        writer.bits(0b100, 3);
+        dynamic.write_header(writer);
+        write_tokens(writer, tokens, &dynamic.literals, &dynamic.distances);
+    }
+}
+
+fn hash(data: &[u8], at: usize) -> usize {
+    let value = (data[at] as u32) << 16 | (data[at + 1] as u32) << 8 | data[at + 2] as u32;
+    (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+// Greedy LZ77 over `data[start..]`, allowed to reach back into `data[..start]`.
+fn tokenize(data: &[u8], start: usize, window: usize) -> Vec<Token> {
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+    let insert = |at: usize, head: &mut [usize], prev: &mut [usize]| {
+        if at + MIN_MATCH <= data.len() {
+            let h = hash(data, at);
+            prev[at] = head[h];
+            head[h] = at;
+        }
+    };
+    for at in start.saturating_sub(window)..start {
+        insert(at, &mut head, &mut prev);
+    }
+
+    let mut tokens = vec![];
+    let mut at = start;
+    while at < data.len() {
+        let mut best = (0, 0);
+        if at + MIN_MATCH <= data.len() {
+            let max = MAX_MATCH.min(data.len() - at);
+            let mut candidate = head[hash(data, at)];
+            let mut chain = 0;
+            while candidate != usize::MAX && at - candidate <= window && chain < MAX_CHAIN {
+                let length = data[candidate..]
+                    .iter()
+                    .zip(&data[at..at + max])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best.0 {
+                    best = (length, at - candidate);
+                    if length == max {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+
+        if best.0 >= MIN_MATCH {
+            tokens.push(Token::Match(best.0 as u16, best.1 as u16));
+            for i in at..at + best.0 {
+                insert(i, &mut head, &mut prev);
+            }
+            at += best.0;
+        } else {
+            tokens.push(Token::Literal(data[at]));
+            insert(at, &mut head, &mut prev);
+            at += 1;
+        }
+    }
+    tokens
+}
+
+pub struct Deflater {
+    window: usize,
+    context_takeover: bool,
+    history: Vec<u8>,
+}
+
+impl Deflater {
+    pub fn new(window_bits: u8, context_takeover: bool) -> Self {
+        let window_bits = window_bits.clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS);
+        Self {
+            window: 1 << window_bits,
+            context_takeover,
+            history: vec![],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.history.clear();
+    }
+
+    // Output is a run of non-final blocks terminated by a sync flush, so it
+    // always ends in an empty stored block (00 00 ff ff).
+    pub fn compress(&mut self, input: &[u8]) -> Vec<u8> {
+        let start = self.history.len();
+        let mut data = std::mem::take(&mut self.history);
+        data.extend_from_slice(input);
+        let tokens = tokenize(&data, start, self.window);
+
+        let mut out = vec![];
+        let mut writer = BitWriter::new(&mut out);
+        let mut offset = start;
+        for block in tokens.chunks(BLOCK_TOKENS) {
+            let raw: usize = block
+                .iter()
+                .map(|token| match token {
+                    Token::Literal(_) => 1,
+                    Token::Match(length, _) => *length as usize,
+                })
+                .sum();
+            write_block(&mut writer, block, &data[offset..offset + raw]);
+            offset += raw;
+        }
+        writer.bits(0b000, 3);
+        writer.align();
+        out.extend_from_slice(&SYNC_FLUSH);
+
+        if self.context_takeover {
+            let keep = data.len().saturating_sub(self.window);
+            data.drain(..keep);
+            self.history = data;
+        }
+        out
+    }
+}
+
+struct BitReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            pos: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, count: u8) -> Result<u32, InflateError> {
+        let count = count as u32;
+        while self.count < count {
+            let byte = *self
+                .input
+                .get(self.pos)
+                .ok_or(InflateError::UnexpectedEof)?;
+            self.pos += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u32 << count) - 1);
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], InflateError> {
+        let bytes = self
+            .input
+            .get(self.pos..self.pos + length)
+            .ok_or(InflateError::UnexpectedEof)?;
+        self.pos += length;
+        Ok(bytes)
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.input.len() && self.count == 0
+    }
+}
+
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, InflateError> {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(InflateError::InvalidCode);
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for bits in 1..15 {
+            offsets[bits + 1] = offsets[bits] + counts[bits];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, InflateError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = count as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError::InvalidCode)
+    }
+}
+
+fn inflate_codes(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    limit: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), InflateError> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 if out.len() >= limit => return Err(InflateError::OutputLimit),
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let symbol = symbol - 257;
+                if symbol >= LENGTH_BASE.len() {
+                    return Err(InflateError::InvalidCode);
+                }
+                let length =
+                    LENGTH_BASE[symbol] as usize + reader.bits(LENGTH_EXTRA[symbol])? as usize;
+                let symbol = distances.decode(reader)? as usize;
+                if symbol >= DIST_BASE.len() {
+                    return Err(InflateError::InvalidCode);
+                }
+                let distance =
+                    DIST_BASE[symbol] as usize + reader.bits(DIST_EXTRA[symbol])? as usize;
+                if distance > out.len() {
+                    return Err(InflateError::InvalidDistance);
+                }
+                if out.len() + length > limit {
+                    return Err(InflateError::OutputLimit);
+                }
+                let from = out.len() - distance;
+                for i in 0..length {
+                    out.push(out[from + i]);
+                }
+            }
+        }
+    }
+}
+
+fn dynamic_tables(reader: &mut BitReader) -> Result<(Huffman, Huffman), InflateError> {
+    let hlit = reader.bits(5)? as usize + 257;
+    let hdist = reader.bits(5)? as usize + 1;
+    let hclen = reader.bits(4)? as usize + 4;
+    if hlit > 286 || hdist > 30 {
+        return Err(InflateError::InvalidCode);
+    }
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_ORDER[..hclen] {
+        code_lengths[symbol] = reader.bits(3)? as u8;
+    }
+    let codes = Huffman::new(&code_lengths)?;
+
+    let mut lengths = Vec::with_capacity(hlit + hdist);
+    while lengths.len() < hlit + hdist {
+        let (value, repeat) = match codes.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or(InflateError::InvalidCode)?;
+                (previous, 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if lengths.len() + repeat > hlit + hdist {
+            return Err(InflateError::InvalidCode);
+        }
+        lengths.extend(std::iter::repeat_n(value, repeat));
+    }
+    if lengths[256] == 0 {
+        return Err(InflateError::InvalidCode);
+    }
+    Ok((
+        Huffman::new(&lengths[..hlit])?,
+        Huffman::new(&lengths[hlit..])?,
+    ))
+}
+
+pub struct Inflater {
+    context_takeover: bool,
+    history: Vec<u8>,
+}
+
+impl Inflater {
+    pub fn new(context_takeover: bool) -> Self {
+        Self {
+            context_takeover,
+            history: vec![],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.history.clear();
+    }
+
+    // Decodes blocks until the input runs out or a final block is seen,
+    // giving up as soon as the output would pass `max` bytes.
+    pub fn decompress(&mut self, input: &[u8], max: usize) -> Result<Vec<u8>, InflateError> {
+        let start = self.history.len();
+        let limit = start.saturating_add(max);
+        let mut out = std::mem::take(&mut self.history);
+        let mut reader = BitReader::new(input);
+        while !reader.at_end() {
+            let last = reader.bits(1)? == 1;
+            match reader.bits(2)? {
+                0 => {
+                    reader.align();
+                    let header = reader.bytes(4)?;
+                    let length = u16::from_le_bytes([header[0], header[1]]);
+                    if length != !u16::from_le_bytes([header[2], header[3]]) {
+                        return Err(InflateError::InvalidStoredLength);
+                    }
+                    if out.len() + length as usize > limit {
+                        return Err(InflateError::OutputLimit);
+                    }
+                    out.extend_from_slice(reader.bytes(length as usize)?);
+                }
+                1 => {
+                    let (literals, distances) = fixed_lengths();
+                    let literals = Huffman::new(&literals)?;
+                    let distances = Huffman::new(&distances)?;
+                    inflate_codes(&mut reader, &mut out, limit, &literals, &distances)?;
+                }
+                2 => {
+                    let (literals, distances) = dynamic_tables(&mut reader)?;
+                    inflate_codes(&mut reader, &mut out, limit, &literals, &distances)?;
+                }
+                _ => return Err(InflateError::InvalidBlockType),
+            }
+            if last {
+                break;
+            }
+        }
+
+        let message = out[start..].to_vec();
+        if self.context_takeover {
+            let keep = out.len().saturating_sub(MAX_WINDOW);
+            out.drain(..keep);
+            self.history = out;
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Deflater, InflateError, Inflater};
+
+    #[test]
+    fn rfc7692_examples() {
+        let mut inflater = Inflater::new(true);
+        let hello = [
+            0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x00, 0x00, 0xff, 0xff,
+        ];
+        assert_eq!(inflater.decompress(&hello, usize::MAX).unwrap(), b"Hello");
+        let again = [0xf2, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff];
+        assert_eq!(inflater.decompress(&again, usize::MAX).unwrap(), b"Hello");
+
+        let stored = [
+            0x00, 0x05, 0x00, 0xfa, 0xff, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x00, 0x00, 0x00, 0xff,
+            0xff,
+        ];
+        assert_eq!(
+            Inflater::new(false).decompress(&stored, 5).unwrap(),
+            b"Hello"
+        );
+        let mut deflater = Deflater::new(15, false);
+        assert_eq!(deflater.compress(b""), [0x00, 0x00, 0x00, 0xff, 0xff]);
+        assert!(Inflater::new(false)
+            .decompress(&[0xf2, 0x48], usize::MAX)
+            .is_err());
+        assert_eq!(
+            Inflater::new(false).decompress(&stored, 4),
+            Err(InflateError::OutputLimit)
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        let json = br#"{"id":1,"event":"update","payload":{"price":10.5,"qty":3}}"#.repeat(200);
+        let noise: Vec<u8> = (0..70_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let inputs: [&[u8]; 4] = [b"", b"ab", &json, &noise];
+
+        for (window_bits, takeover) in [(15, true), (15, false), (9, true)] {
+            let mut deflater = Deflater::new(window_bits, takeover);
+            let mut inflater = Inflater::new(takeover);
+            for input in inputs.iter().chain(inputs.iter()) {
+                let compressed = deflater.compress(input);
+                assert_eq!(
+                    &inflater.decompress(&compressed, usize::MAX).unwrap(),
+                    input
+                );
+            }
+        }
+        let compressed = Deflater::new(15, false).compress(&json);
+        assert!(compressed.len() * 10 < json.len());
+
+        let bomb = Deflater::new(15, false).compress(&vec![0; 1 << 20]);
+        for max in [0, 1000, (1 << 20) - 1] {
+            assert_eq!(
+                Inflater::new(false).decompress(&bomb, max),
+                Err(InflateError::OutputLimit)
+            );
+        }
+        assert_eq!(
+            Inflater::new(false)
+                .decompress(&bomb, 1 << 20)
+                .unwrap()
+                .len(),
+            1 << 20
+        );
+    }
+}
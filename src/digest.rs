@@ -0,0 +1,17 @@
+// A hash function fed incrementally. `finalize` consumes the state, so a
+// context is used for one message; clone it to hash several messages that
+// share a prefix.
+pub trait Digest: Default + Clone {
+    const BLOCK_SIZE: usize;
+    const OUTPUT_SIZE: usize;
+    type Output: AsRef<[u8]> + Copy;
+
+    fn update(&mut self, data: &[u8]);
+    fn finalize(self) -> Self::Output;
+
+    fn digest(data: &[u8]) -> Self::Output {
+        let mut ctx = Self::default();
+        ctx.update(data);
+        ctx.finalize()
+    }
+}
@@ -0,0 +1,316 @@
+use std::{fmt, sync::Arc};
+
+use crate::frame::Opcode;
+
+// An extension negotiated through Sec-WebSocket-Extensions. Negotiation
+// methods receive a single header element ("name; param=value; ...") and
+// the instance keeps whatever state was agreed for its connection.
+pub trait Extension: Send {
+    fn name(&self) -> &str;
+
+    // RSV bits this extension may set, as they sit in the first header byte.
+    fn rsv(&self) -> u8 {
+        0
+    }
+
+    fn offer(&self) -> String {
+        self.name().to_string()
+    }
+
+    // Server side: returns the response element when the offer is acceptable.
+    fn accept(&mut self, _offer: &str) -> Option<String> {
+        Some(self.name().to_string())
+    }
+
+    // Client side: validates the server's response element.
+    fn confirm(&mut self, _response: &str) -> bool {
+        true
+    }
+
+    fn encode(&mut self, _opcode: Opcode, _rsv: &mut u8, payload: Vec<u8>) -> Vec<u8> {
+        payload
+    }
+
+    // `max` bounds the decoded size; an extension that expands payloads
+    // must stop at it and fail with 1009 rather than allocate past it.
+    fn decode(
+        &mut self,
+        _opcode: Opcode,
+        _rsv: u8,
+        payload: Vec<u8>,
+        _max: usize,
+    ) -> Result<Vec<u8>, u16> {
+        Ok(payload)
+    }
+}
+
+pub type ExtensionFactory = Arc<dyn Fn() -> Box<dyn Extension> + Send + Sync>;
+
+// Extensions a server or client is willing to use; every connection gets
+// fresh instances since negotiated state is per connection.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    factories: Vec<ExtensionFactory>,
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.instantiate()
+                    .iter()
+                    .map(|extension| extension.name().to_string()),
+            )
+            .finish()
+    }
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<F>(mut self, factory: F) -> Self
+    where
+        F: Fn() -> Box<dyn Extension> + Send + Sync + 'static,
+    {
+        self.factories.push(Arc::new(factory));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.factories.is_empty()
+    }
+
+    pub fn instantiate(&self) -> Vec<Box<dyn Extension>> {
+        self.factories.iter().map(|factory| factory()).collect()
+    }
+}
+
+pub fn elements(header: &str) -> impl Iterator<Item = &str> {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|element| !element.is_empty())
+}
+
+pub fn element_name(element: &str) -> &str {
+    element.split(';').next().unwrap_or_default().trim()
+}
+
+// The extensions agreed for one connection. Outgoing payloads pass through
+// them in negotiated order and incoming payloads in reverse.
+#[derive(Default)]
+pub struct Chain {
+    extensions: Vec<Box<dyn Extension>>,
+}
+
+impl Chain {
+    pub fn offer(candidates: &[Box<dyn Extension>]) -> Option<String> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let offers: Vec<String> = candidates
+            .iter()
+            .map(|extension| extension.offer())
+            .collect();
+        Some(offers.join(", "))
+    }
+
+    // Walks the client's offers in preference order, taking the first
+    // acceptable offer for each extension whose RSV bits are still free.
+    pub fn accept(candidates: Vec<Box<dyn Extension>>, header: &str) -> (Self, Option<String>) {
+        let mut candidates: Vec<Option<Box<dyn Extension>>> =
+            candidates.into_iter().map(Some).collect();
+        let mut chain = Self::default();
+        let mut response = vec![];
+        for element in elements(header) {
+            let name = element_name(element);
+            let slot = candidates.iter_mut().find(|slot| {
+                slot.as_ref().is_some_and(|extension| {
+                    extension.name() == name && extension.rsv() & chain.rsv() == 0
+                })
+            });
+            if let Some(slot) = slot {
+                let mut extension = slot.take().unwrap();
+                match extension.accept(element) {
+                    Some(answer) => {
+                        response.push(answer);
+                        chain.extensions.push(extension);
+                    }
+                    None => *slot = Some(extension),
+                }
+            }
+        }
+        let response = (!response.is_empty()).then(|| response.join(", "));
+        (chain, response)
+    }
+
+    // Fails if the server picked something we did not offer, picked it
+    // twice, or answered with parameters the extension rejects.
+    pub fn confirm(candidates: Vec<Box<dyn Extension>>, header: Option<&str>) -> Option<Self> {
+        let mut candidates: Vec<Option<Box<dyn Extension>>> =
+            candidates.into_iter().map(Some).collect();
+        let mut chain = Self::default();
+        for element in header.into_iter().flat_map(elements) {
+            let name = element_name(element);
+            let mut confirmed = None;
+            for slot in candidates.iter_mut() {
+                if let Some(extension) = slot.as_mut() {
+                    if extension.name() == name
+                        && extension.rsv() & chain.rsv() == 0
+                        && extension.confirm(element)
+                    {
+                        confirmed = slot.take();
+                        break;
+                    }
+                }
+            }
+            chain.extensions.push(confirmed?);
+        }
+        Some(chain)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.extensions
+            .iter()
+            .map(|extension| extension.name())
+            .collect()
+    }
+
+    pub fn rsv(&self) -> u8 {
+        self.extensions
+            .iter()
+            .fold(0, |rsv, extension| rsv | extension.rsv())
+    }
+
+    pub fn encode(&mut self, opcode: Opcode, payload: Vec<u8>) -> (u8, Vec<u8>) {
+        let mut rsv = 0;
+        let payload = self
+            .extensions
+            .iter_mut()
+            .fold(payload, |payload, extension| {
+                extension.encode(opcode, &mut rsv, payload)
+            });
+        (rsv, payload)
+    }
+
+    pub fn decode(
+        &mut self,
+        opcode: Opcode,
+        rsv: u8,
+        payload: Vec<u8>,
+        max: usize,
+    ) -> Result<Vec<u8>, u16> {
+        if rsv & !self.rsv() != 0 {
+            return Err(1002);
+        }
+        self.extensions
+            .iter_mut()
+            .rev()
+            .try_fold(payload, |payload, extension| {
+                extension.decode(opcode, rsv, payload, max)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chain, Extension, Extensions};
+    use crate::frame::{Opcode, RSV2, RSV3};
+
+    struct Xor {
+        key: u8,
+    }
+
+    impl Extension for Xor {
+        fn name(&self) -> &str {
+            "x-xor"
+        }
+
+        fn rsv(&self) -> u8 {
+            RSV2
+        }
+
+        fn accept(&mut self, offer: &str) -> Option<String> {
+            self.key = offer.split_once("key=")?.1.parse().ok()?;
+            Some(offer.to_string())
+        }
+
+        fn confirm(&mut self, response: &str) -> bool {
+            response.ends_with(&format!("key={}", self.key))
+        }
+
+        fn offer(&self) -> String {
+            format!("x-xor; key={}", self.key)
+        }
+
+        fn encode(&mut self, _opcode: Opcode, rsv: &mut u8, payload: Vec<u8>) -> Vec<u8> {
+            *rsv |= RSV2;
+            payload.into_iter().map(|byte| byte ^ self.key).collect()
+        }
+
+        fn decode(
+            &mut self,
+            _opcode: Opcode,
+            rsv: u8,
+            payload: Vec<u8>,
+            _max: usize,
+        ) -> Result<Vec<u8>, u16> {
+            match rsv & RSV2 {
+                0 => Ok(payload),
+                _ => Ok(payload.into_iter().map(|byte| byte ^ self.key).collect()),
+            }
+        }
+    }
+
+    struct Marker;
+
+    impl Extension for Marker {
+        fn name(&self) -> &str {
+            "x-marker"
+        }
+    }
+
+    #[test]
+    fn negotiate_and_transform() {
+        let server = Extensions::new()
+            .with(|| Box::new(Marker))
+            .with(|| Box::new(Xor { key: 0 }));
+        let client = Extensions::new()
+            .with(|| Box::new(Xor { key: 7 }))
+            .with(|| Box::new(Xor { key: 9 }));
+
+        let offer = Chain::offer(&client.instantiate()).unwrap();
+        assert_eq!(offer, "x-xor; key=7, x-xor; key=9");
+        let (mut server_chain, response) =
+            Chain::accept(server.instantiate(), &format!("x-unknown, {offer}"));
+        let response = response.unwrap();
+        assert_eq!(response, "x-xor; key=7");
+        assert_eq!(server_chain.names(), ["x-xor"]);
+
+        let mut client_chain = Chain::confirm(client.instantiate(), Some(&response)).unwrap();
+        let (rsv, encoded) = client_chain.encode(Opcode::Text, b"hello".to_vec());
+        assert_eq!(rsv, RSV2);
+        assert_ne!(encoded, b"hello");
+        assert_eq!(
+            server_chain.decode(Opcode::Text, rsv, encoded, usize::MAX),
+            Ok(b"hello".to_vec())
+        );
+        assert_eq!(
+            server_chain.decode(Opcode::Text, RSV3, vec![], usize::MAX),
+            Err(1002)
+        );
+
+        assert!(Chain::confirm(client.instantiate(), Some("x-marker")).is_none());
+        assert!(Chain::confirm(client.instantiate(), Some("x-xor; key=9")).is_some());
+        assert!(Chain::confirm(client.instantiate(), Some("x-xor; key=8")).is_none());
+        assert!(Chain::confirm(client.instantiate(), None)
+            .unwrap()
+            .is_empty());
+    }
+}
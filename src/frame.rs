@@ -1,14 +1,23 @@
-//#[allow()]
-use std::{io::Read, net::TcpStream};
+use std::{
+    io::{self, IoSlice, Read, Write},
+    ops::Deref,
+};
 
 pub const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
-#[derive(Debug, Copy, Clone)]
+pub const RSV1: u8 = 0x40;
+pub const RSV2: u8 = 0x20;
+pub const RSV3: u8 = 0x10;
+
+// Two fixed bytes, an eight-byte extended length and a four-byte mask.
+pub const MAX_HEADER_SIZE: usize = 14;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Opcode {
     Continuation,
     Text,
     Binary,
-    Reserved,
+    Reserved(u8),
     Close,
     Ping,
     Pong,
@@ -23,13 +32,13 @@ impl From<u8> for Opcode {
             0x8 => Self::Close,
             0x9 => Self::Ping,
             0xa => Self::Pong,
-            _ => Self::Reserved,
+            other => Self::Reserved(other & 0xf),
         }
     }
 }
 
 impl Opcode {
-    fn into_u8(self) -> u8 {
+    pub fn into_u8(self) -> u8 {
         match self {
             Self::Continuation => 0x0,
             Self::Text => 0x1,
@@ -37,14 +46,19 @@ impl Opcode {
             Self::Close => 0x8,
             Self::Ping => 0x9,
             Self::Pong => 0xa,
-            Self::Reserved => 0xb,
+            Self::Reserved(value) => value & 0xf,
         }
     }
+
+    pub fn is_control(self) -> bool {
+        self.into_u8() & 0x8 != 0
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Frame {
     pub is_final: bool,
+    pub rsv: u8,
     pub opcode: Opcode,
     pub mask: Option<[u8; 4]>,
     pub payload_length: usize,
@@ -59,92 +73,285 @@ impl Frame {
     ) -> Self {
         Self {
             is_final,
+            rsv: 0,
             opcode,
             mask,
             payload_length,
         }
     }
 
-    pub fn to_blob(&self) -> Vec<u8> {
-        let mut blob = vec![];
-        let mut first_byte = 0x0u8;
+    pub fn header(&self) -> Header {
+        let mut bytes = [0u8; MAX_HEADER_SIZE];
+        bytes[0] = self.rsv & (RSV1 | RSV2 | RSV3) | self.opcode.into_u8();
         if self.is_final {
-            first_byte |= 0x80;
+            bytes[0] |= 0x80;
         }
-        first_byte |= self.opcode.into_u8();
-        blob.push(first_byte);
-        let mut second_byte = 0x0u8;
         if self.mask.is_some() {
-            second_byte |= 0x80;
+            bytes[1] = 0x80;
         }
 
+        let mut len = 2;
         if self.payload_length < 126 {
-            second_byte |= self.payload_length as u8;
-            blob.push(second_byte);
+            bytes[1] |= self.payload_length as u8;
         } else if self.payload_length <= u16::MAX as usize {
-            second_byte |= 126;
-            blob.push(second_byte);
-            let _ = u16::to_be_bytes(self.payload_length as u16)
-                .bytes()
-                .map(|b| blob.push(b.unwrap()));
+            bytes[1] |= 126;
+            bytes[2..4].copy_from_slice(&(self.payload_length as u16).to_be_bytes());
+            len = 4;
         } else {
-            second_byte |= 127;
-            blob.push(second_byte);
-            let _ = u64::to_be_bytes(self.payload_length as u64)
-                .bytes()
-                .map(|b| blob.push(b.unwrap()));
+            bytes[1] |= 127;
+            bytes[2..10].copy_from_slice(&(self.payload_length as u64).to_be_bytes());
+            len = 10;
         }
-        match self.mask {
-            Some(mask) => {
-                mask.bytes().for_each(|b| 
-                    blob.push(b.unwrap())
-                )
-            },
-            None => {},
+        if let Some(mask) = self.mask {
+            bytes[len..len + 4].copy_from_slice(&mask);
+            len += 4;
         }
 
-        blob
+        Header { bytes, len }
     }
 
-    pub fn read_frame(mut stream: &TcpStream) -> Self {
-        let mut buffer = [0u8; 2];
-        stream
-            .read_exact(&mut buffer)
-            .expect("failed to read from stream");
-        let n = buffer[0];
-        let is_final = (n & 0x80) > 0;
-        let opcode = n & 0xf;
-        let n = buffer[1];
-        let mask = (n & 0x80) > 0;
-        let payload_len = n & 0x7f;
+    pub fn to_blob(&self) -> Vec<u8> {
+        self.header().to_vec()
+    }
+
+    // Ok(None) until the whole header is buffered. Err carries the close
+    // code for a length with the most significant bit set, which RFC 6455
+    // forbids, or one too large to address here.
+    pub fn parse(buf: &[u8]) -> Result<Option<(Self, usize)>, u16> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let is_final = (buf[0] & 0x80) > 0;
+        let rsv = buf[0] & (RSV1 | RSV2 | RSV3);
+        let opcode = buf[0] & 0xf;
+        let mask = (buf[1] & 0x80) > 0;
+        let payload_len = buf[1] & 0x7f;
 
-        let real_len = if payload_len < 126 {
-            payload_len as usize
+        let (real_len, mut offset) = if payload_len < 126 {
+            (payload_len as usize, 2)
         } else if payload_len == 126 {
-            stream
-                .read_exact(&mut buffer)
-                .expect("failed to read from stream");
-            u16::from_be_bytes(buffer) as usize
+            let Some(bytes) = buf.get(2..4) else {
+                return Ok(None);
+            };
+            (u16::from_be_bytes([bytes[0], bytes[1]]) as usize, 4)
+        } else {
+            let Some(bytes) = buf.get(2..10) else {
+                return Ok(None);
+            };
+            let len = u64::from_be_bytes(bytes.try_into().unwrap());
+            if len >> 63 != 0 {
+                return Err(1002);
+            }
+            (usize::try_from(len).map_err(|_| 1009u16)?, 10)
+        };
+
+        let mask = if mask {
+            let Some(bytes) = buf.get(offset..offset + 4) else {
+                return Ok(None);
+            };
+            offset += 4;
+            Some([bytes[0], bytes[1], bytes[2], bytes[3]])
         } else {
-            let mut buffer = [0u8; 8];
-            stream
-                .read_exact(&mut buffer)
-                .expect("failed to read from stream");
-            u64::from_be_bytes(buffer) as usize
+            None
         };
 
-        let mut buffer = [0u8; 4];
-        if mask {
-            stream
-                .read_exact(&mut buffer)
-                .expect("failed to read from stream");
+        Ok(Some((
+            Self {
+                is_final,
+                rsv,
+                opcode: opcode.into(),
+                mask,
+                payload_length: real_len,
+            },
+            offset,
+        )))
+    }
+
+    // Reads one header off a blocking stream; the length checks are the
+    // ones `parse` applies to buffered bytes.
+    pub fn read_frame<R: Read + ?Sized>(stream: &mut R) -> io::Result<Self> {
+        let mut buf = [0u8; MAX_HEADER_SIZE];
+        stream.read_exact(&mut buf[..2])?;
+        let mut len = match buf[1] & 0x7f {
+            126 => 4,
+            127 => 10,
+            _ => 2,
+        };
+        if buf[1] & 0x80 != 0 {
+            len += 4;
         }
+        stream.read_exact(&mut buf[2..len])?;
+        let parsed = Self::parse(&buf[..len]).map_err(|code| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid frame header (close code {code})"),
+            )
+        })?;
+        parsed
+            .map(|(frame, _)| frame)
+            .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+}
 
-        Self {
-            is_final,
-            opcode: opcode.into(),
-            mask: if mask { Some(buffer) } else { None },
-            payload_length: real_len,
+// An encoded frame header, built on the stack.
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    bytes: [u8; MAX_HEADER_SIZE],
+    len: usize,
+}
+
+impl Deref for Header {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+// Masks eight bytes at a time; `offset` is the position of `data` within
+// the payload, so a payload can be masked in pieces.
+pub fn apply_mask(data: &mut [u8], mask: [u8; 4], offset: usize) {
+    let mut mask = mask;
+    mask.rotate_left(offset % 4);
+    let word = u64::from_ne_bytes([
+        mask[0], mask[1], mask[2], mask[3], mask[0], mask[1], mask[2], mask[3],
+    ]);
+    let mut chunks = data.chunks_exact_mut(8);
+    for chunk in &mut chunks {
+        let masked = u64::from_ne_bytes((&*chunk).try_into().unwrap()) ^ word;
+        chunk.copy_from_slice(&masked.to_ne_bytes());
+    }
+    for (byte, mask) in chunks.into_remainder().iter_mut().zip(mask.iter().cycle()) {
+        *byte ^= mask;
+    }
+}
+
+// Writes the header and an already masked payload with vectored writes,
+// so the payload is never copied into a frame buffer.
+pub fn write_frame<W: Write + ?Sized>(
+    writer: &mut W,
+    frame: &Frame,
+    payload: &[u8],
+) -> io::Result<()> {
+    let header = frame.header();
+    let mut slices = [IoSlice::new(&header), IoSlice::new(payload)];
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_mask, write_frame, Frame, Opcode, RSV1, RSV2, RSV3};
+    use std::io;
+
+    #[test]
+    fn parse_roundtrip() {
+        for len in [0usize, 125, 126, 65535, 65536] {
+            let mut frame = Frame::new(true, Opcode::Binary, Some([1, 2, 3, 4]), len);
+            frame.rsv = if len % 2 == 1 { RSV1 | RSV3 } else { 0 };
+            let blob = frame.to_blob();
+            let (parsed, header) = Frame::parse(&blob).unwrap().unwrap();
+            assert_eq!(header, blob.len());
+            assert_eq!(parsed.payload_length, len);
+            assert_eq!(parsed.opcode, Opcode::Binary);
+            assert_eq!(parsed.rsv, frame.rsv);
+            assert_eq!(parsed.mask, Some([1, 2, 3, 4]));
+            assert_eq!(Frame::parse(&blob[..header - 1]), Ok(None));
         }
+
+        let (frame, _) = Frame::parse(&[0x33, 0x00]).unwrap().unwrap();
+        assert_eq!(
+            (frame.rsv, frame.opcode),
+            (RSV2 | RSV3, Opcode::Reserved(0x3))
+        );
+        assert_eq!(frame.to_blob(), [0x33, 0x00]);
+
+        let mut huge = vec![0x82, 0xff];
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        huge.extend_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(Frame::parse(&huge), Err(1002));
+        let err = Frame::read_frame(&mut &huge[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let blob = Frame::new(true, Opcode::Text, Some([1, 2, 3, 4]), 70000).to_blob();
+        let frame = Frame::read_frame(&mut &blob[..]).unwrap();
+        assert_eq!(
+            (frame.payload_length, frame.mask),
+            (70000, Some([1, 2, 3, 4]))
+        );
+        let err = Frame::read_frame(&mut &blob[..5]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn mask_is_involution() {
+        let mut data = *b"hello world";
+        apply_mask(&mut data, [9, 8, 7, 6], 3);
+        apply_mask(&mut data, [9, 8, 7, 6], 3);
+        assert_eq!(&data, b"hello world");
+
+        let payload: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let bytewise: Vec<u8> = payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        let mut whole = payload.clone();
+        apply_mask(&mut whole, mask, 0);
+        assert_eq!(whole, bytewise);
+        let mut pieces = payload.clone();
+        for start in (0..pieces.len()).step_by(7) {
+            let end = (start + 7).min(pieces.len());
+            apply_mask(&mut pieces[start..end], mask, start);
+        }
+        assert_eq!(pieces, bytewise);
+    }
+
+    #[test]
+    fn vectored_write() {
+        let payload = vec![0x5a; 70000];
+        let frame = Frame::new(true, Opcode::Binary, None, payload.len());
+        let mut out = vec![];
+        write_frame(&mut out, &frame, &payload).unwrap();
+        assert_eq!(out.len(), 10 + payload.len());
+        assert_eq!(&out[..10], &frame.to_blob()[..]);
+        assert_eq!(Frame::parse(&out).unwrap().unwrap().0.payload_length, 70000);
+        assert_eq!(&out[10..], &payload[..]);
+    }
+
+    // Accepts at most a few bytes per call, like a full socket buffer.
+    struct Trickle(Vec<u8>);
+
+    impl std::io::Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(7);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn short_writes_resume() {
+        let mut payload = b"a masked payload longer than one write".to_vec();
+        let frame = Frame::new(true, Opcode::Text, Some([1, 2, 3, 4]), payload.len());
+        apply_mask(&mut payload, [1, 2, 3, 4], 0);
+        let mut out = Trickle(vec![]);
+        write_frame(&mut out, &frame, &payload).unwrap();
+        let header = frame.header();
+        assert_eq!(&out.0[..header.len()], &header[..]);
+        assert_eq!(&out.0[header.len()..], &payload[..]);
     }
 }
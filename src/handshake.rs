@@ -1,48 +1,401 @@
-use std::{io::{BufRead, BufReader, Error, Write}, net::{TcpListener, TcpStream}};
+use std::{io::{Error, ErrorKind, Read, Write}, net::{TcpListener, TcpStream}};
 
-use crate::{base64, frame::GUID, sha1};
+use crate::{base64, frame::GUID, http::{self, HttpConfig}, sha1::Sha1Ctx};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_size: usize,
+    pub max_headers: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_size: 8192,
+            max_headers: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn parse(buf: &[u8], limits: &Limits) -> Result<Option<(Self, usize)>, Response> {
+        let (start, headers, used) = match parse_head(buf, limits)? {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        let mut request_line = start.split(' ');
+        let (method, path, version) = match (
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+        ) {
+            (Some(method), Some(path), Some(version)) => (method, path, version),
+            _ => return Err(Response::new(400)),
+        };
+        if !version.starts_with("HTTP/1.") {
+            return Err(Response::new(505));
+        }
+
+        Ok(Some((
+            Self {
+                method: method.to_string(),
+                path: path.to_string(),
+                headers,
+            },
+            used,
+        )))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn header_values(&self, name: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        has_token(&self.headers, name, token)
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        self.header("Sec-WebSocket-Key")
+    }
+
+    // Checks an upgrade request against RFC 6455 §4.2.1 and returns its
+    // key, or the response to refuse it with.
+    pub fn validate(&self) -> Result<&str, Response> {
+        if self.method != "GET"
+            || !self.has_token("Connection", "upgrade")
+            || !self.has_token("Upgrade", "websocket")
+        {
+            return Err(Response::new(400));
+        }
+        if self.header("Sec-WebSocket-Version") != Some("13") {
+            return Err(Response::new(426).header("Sec-WebSocket-Version", "13"));
+        }
+        self.key().ok_or_else(|| Response::new(400))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, self.path);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+type Head<'a> = (&'a str, Vec<(String, String)>, usize);
+
+fn parse_head<'a>(buf: &'a [u8], limits: &Limits) -> Result<Option<Head<'a>>, Response> {
+    let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None if buf.len() >= limits.max_size => return Err(Response::new(431)),
+        None => return Ok(None),
+    };
+    if end + 4 > limits.max_size {
+        return Err(Response::new(431));
+    }
+    let head = std::str::from_utf8(&buf[..end]).map_err(|_| Response::new(400))?;
+    let mut lines = head.split("\r\n");
+    let start = lines.next().unwrap_or_default();
+
+    let mut headers = vec![];
+    for line in lines {
+        if headers.len() == limits.max_headers {
+            return Err(Response::new(431));
+        }
+        match line.split_once(':') {
+            Some((name, value)) => headers.push((name.trim().to_string(), value.trim().to_string())),
+            None => return Err(Response::new(400)),
+        }
+    }
+    Ok(Some((start, headers, end + 4)))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    pub fn switching_protocols(key: &str) -> Self {
+        Self::new(101)
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Accept", &accept_key(key))
+    }
+
+    pub fn parse(buf: &[u8], limits: &Limits) -> Result<Option<(Self, usize)>, Response> {
+        let (start, headers, used) = match parse_head(buf, limits)? {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        let status = match start.split(' ').collect::<Vec<_>>()[..] {
+            [version, status, ..] if version.starts_with("HTTP/1.") => status.parse().ok(),
+            _ => None,
+        };
+        match status {
+            Some(status) => Ok(Some((
+                Self {
+                    status,
+                    headers,
+                    body: vec![],
+                },
+                used,
+            ))),
+            None => Err(Response::new(400)),
+        }
+    }
+
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        has_token(&self.headers, name, token)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: &[u8]) -> Self {
+        self.body = body.to_vec();
+        self
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self.status {
+            101 => "Switching Protocols",
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            505 => "HTTP Version Not Supported",
+            _ => "",
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason());
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if self.status != 101 {
+            head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+// Whether any `name` header lists `token` among its comma-separated values,
+// in any case.
+fn has_token(headers: &[(String, String)], name: &str, token: &str) -> bool {
+    headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut ctx = Sha1Ctx::new();
+    ctx.update(key.as_bytes());
+    ctx.update(GUID.as_bytes());
+    base64::encode(&ctx.finalize())
+}
 
 pub fn new_connection(listener: &TcpListener) -> Result<TcpStream, Error> {
-    let mut ctx = sha1::Sha1Ctx::new();
     match listener.accept() {
         Ok((mut stream, _)) => {
-            let mut key = get_key(&mut stream);
-            key.push_str(GUID);
-            let key = ctx.digest(&key);
-            let key = base64::encode(&key);
-            let accept = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", key);
-            println!(
-                "new connection from {:?} . key = {:?}",
-                stream.peer_addr(),
-                key
-            );
-            stream.write_all(accept.as_bytes()).unwrap();
+            upgrade(&mut stream)?;
             Ok(stream)
         }
         Err(e) => Err(e),
     }
 }
 
-pub fn get_key(stream: &mut TcpStream) -> String {
-    let mut buffer = String::new();
-    let mut reader = BufReader::new(stream);
-    let mut key: Option<String> = None;
+// Blocking server handshake over any byte stream; returns the accept key.
+// Plain HTTP requests get the default fallback response and an error.
+pub fn upgrade<S: Read + Write>(stream: &mut S) -> Result<String, Error> {
+    let request = read_request(stream)?;
+    let key = if http::is_upgrade(&request) {
+        request.validate()
+    } else {
+        Err(HttpConfig::default().respond(&request))
+    };
+    match key {
+        Ok(key) => {
+            stream.write_all(&Response::switching_protocols(key).to_bytes())?;
+            Ok(accept_key(key))
+        }
+        Err(response) => {
+            stream.write_all(&response.to_bytes())?;
+            Err(Error::new(ErrorKind::InvalidData, "not a WebSocket upgrade"))
+        }
+    }
+}
+
+pub fn get_key<S: Read>(stream: &mut S) -> Result<Option<String>, Error> {
+    Ok(read_request(stream)?.key().map(str::to_string))
+}
+
+// Reads one request head a byte at a time so that frames sent right behind
+// it stay in the stream.
+fn read_request<S: Read>(stream: &mut S) -> Result<Request, Error> {
+    let limits = Limits::default();
+    let mut buffer = vec![];
+    let mut byte = [0u8; 1];
     loop {
-        match reader.read_line(&mut buffer) {
-            Ok(size) => {
-                if size == 2 {
-                    break;
-                }
-                let fields = buffer.trim().split(": ").collect::<Vec<&str>>();
-                if fields[0] == "Sec-WebSocket-Key" {
-                    key = Some(fields[1].to_string());
-                }
-                buffer.clear();
-            }
-            Err(e) => {
-                panic!("{}", e)
-            }
-        }
-    }
-    key.unwrap()
+        stream.read_exact(&mut byte)?;
+        buffer.push(byte[0]);
+        if buffer.ends_with(b"\r\n\r\n") || buffer.len() >= limits.max_size {
+            return match Request::parse(&buffer, &limits) {
+                Ok(Some((request, _))) => Ok(request),
+                _ => Err(Error::new(ErrorKind::InvalidData, "malformed request head")),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+    };
+
+    use super::{accept_key, get_key, upgrade, Limits, Request, Response};
+
+    #[test]
+    fn rfc_accept_key() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn blocking_upgrade() {
+        let mut raw = &b"GET / HTTP/1.1\r\nSec-WebSocket-Key: abc\r\n\r\n\x81"[..];
+        assert_eq!(get_key(&mut raw).unwrap().as_deref(), Some("abc"));
+        assert_eq!(raw, b"\x81");
+
+        let (mut local, mut remote) = UnixStream::pair().unwrap();
+        local.write_all(b"GET /healthz HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert!(upgrade(&mut remote).is_err());
+        drop(remote);
+        let mut response = String::new();
+        local.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("ok\n"));
+        assert!(get_key(&mut &b"GET / HTTP/1.1\r\n"[..]).is_err());
+
+        for (raw, status) in [
+            (&b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n"[..], "400"),
+            (&b"GET / HTTP/1.1\r\nSec-WebSocket-Key: abc\r\n\r\n"[..], "426"),
+            (&b"POST / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: abc\r\n\r\n"[..], "400"),
+            (&b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\
+                Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: abc\r\n\r\n"[..], "400"),
+            (&b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                Sec-WebSocket-Key: abc\r\n\r\n"[..], "426"),
+            (&b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                Sec-WebSocket-Version: 8\r\nSec-WebSocket-Key: abc\r\n\r\n"[..], "426"),
+        ] {
+            let (mut local, mut remote) = UnixStream::pair().unwrap();
+            local.write_all(raw).unwrap();
+            assert!(upgrade(&mut remote).is_err());
+            drop(remote);
+            let mut response = String::new();
+            local.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with(&format!("HTTP/1.1 {status} ")));
+        }
+    }
+
+    #[test]
+    fn parse_request() {
+        let raw = b"GET /chat HTTP/1.1\r\nHost: x\r\nsec-websocket-key: abc\r\n\r\nrest";
+        let limits = Limits::default();
+        let (request, used) = Request::parse(raw, &limits).unwrap().unwrap();
+        assert_eq!(request.path, "/chat");
+        assert_eq!(request.key(), Some("abc"));
+        assert_eq!(&raw[used..], b"rest");
+        assert_eq!(Request::parse(&raw[..20], &limits), Ok(None));
+
+        let tight = Limits {
+            max_size: 1024,
+            max_headers: 1,
+        };
+        assert_eq!(Request::parse(raw, &tight), Err(Response::new(431)));
+        assert_eq!(Request::parse(&[b'a'; 1024], &tight), Err(Response::new(431)));
+        assert_eq!(Request::parse(b"nonsense\r\n\r\n", &tight), Err(Response::new(400)));
+    }
+
+    #[test]
+    fn validate_upgrade() {
+        let request = |method: &str, skip: &str, version: &str| Request {
+            method: method.to_string(),
+            path: "/".to_string(),
+            headers: [
+                ("Upgrade", "websocket"),
+                ("Connection", "keep-alive, Upgrade"),
+                ("Sec-WebSocket-Version", version),
+                ("Sec-WebSocket-Key", "abc"),
+            ]
+            .into_iter()
+            .filter(|(name, _)| *name != skip)
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        };
+        let upgrade_required = Response::new(426).header("Sec-WebSocket-Version", "13");
+
+        assert_eq!(request("GET", "", "13").validate(), Ok("abc"));
+        assert_eq!(request("POST", "", "13").validate(), Err(Response::new(400)));
+        assert_eq!(request("GET", "Connection", "13").validate(), Err(Response::new(400)));
+        assert_eq!(request("GET", "Upgrade", "13").validate(), Err(Response::new(400)));
+        assert_eq!(request("GET", "Sec-WebSocket-Key", "13").validate(), Err(Response::new(400)));
+        assert_eq!(request("GET", "", "8").validate(), Err(upgrade_required.clone()));
+        assert_eq!(request("GET", "Sec-WebSocket-Version", "").validate(), Err(upgrade_required));
+    }
 }
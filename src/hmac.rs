@@ -0,0 +1,97 @@
+use crate::{
+    digest::Digest,
+    sha1::{Sha1Ctx, SHA1_HASH_SIZE},
+    sha256::{Sha256Ctx, SHA256_HASH_SIZE},
+};
+
+// HMAC (RFC 2104) over any digest; keys longer than a block are hashed first.
+pub fn hmac<D: Digest>(key: &[u8], message: &[u8]) -> D::Output {
+    let mut block = vec![0u8; D::BLOCK_SIZE];
+    if key.len() > D::BLOCK_SIZE {
+        block[..D::OUTPUT_SIZE].copy_from_slice(D::digest(key).as_ref());
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = D::default();
+    inner.update(&block.iter().map(|byte| byte ^ 0x36).collect::<Vec<u8>>());
+    inner.update(message);
+    let mut outer = D::default();
+    outer.update(&block.iter().map(|byte| byte ^ 0x5c).collect::<Vec<u8>>());
+    outer.update(inner.finalize().as_ref());
+    outer.finalize()
+}
+
+pub fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; SHA1_HASH_SIZE] {
+    hmac::<Sha1Ctx>(key, message)
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; SHA256_HASH_SIZE] {
+    hmac::<Sha256Ctx>(key, message)
+}
+
+// Compares without an early exit, so timing reveals only the lengths.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    std::hint::black_box(difference) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{constant_time_eq, hmac_sha1, hmac_sha256};
+
+    fn hex(digest: impl AsRef<[u8]>) -> String {
+        digest
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    // RFC 2202 test cases 1 and 6.
+    #[test]
+    fn rfc_2202() {
+        assert_eq!(
+            hex(hmac_sha1(&[0x0b; 20], b"Hi There")),
+            "b617318655057264e28bc0b6fb378c8ef146be00"
+        );
+        assert_eq!(
+            hex(hmac_sha1(
+                &[0xaa; 80],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+    }
+
+    // RFC 4231 test cases 1, 2 and 6.
+    #[test]
+    fn rfc_4231() {
+        assert_eq!(
+            hex(hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            hex(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn comparison() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
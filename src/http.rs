@@ -0,0 +1,162 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::handshake::{Request, Response};
+
+pub type Responder = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+// Answers plain HTTP requests arriving on a WebSocket listener: the health
+// path first, then files under `static_dir`, then `responder`. Anything left
+// gets 426 pointing the client at the WebSocket upgrade.
+#[derive(Clone)]
+pub struct HttpConfig {
+    pub health: Option<String>,
+    pub static_dir: Option<PathBuf>,
+    pub responder: Option<Responder>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            health: Some("/healthz".to_string()),
+            static_dir: None,
+            responder: None,
+        }
+    }
+}
+
+impl fmt::Debug for HttpConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpConfig")
+            .field("health", &self.health)
+            .field("static_dir", &self.static_dir)
+            .field("responder", &self.responder.is_some())
+            .finish()
+    }
+}
+
+impl HttpConfig {
+    pub fn respond(&self, request: &Request) -> Response {
+        let path = request.path.split('?').next().unwrap_or_default();
+        if request.method == "GET" {
+            if self.health.as_deref() == Some(path) {
+                return Response::new(200)
+                    .header("Content-Type", "text/plain")
+                    .body(b"ok\n");
+            }
+            if let Some(response) = self.static_dir.as_deref().and_then(|dir| serve(dir, path)) {
+                return response;
+            }
+        }
+        match &self.responder {
+            Some(responder) => responder(request),
+            None => Response::new(426).header("Upgrade", "websocket"),
+        }
+    }
+}
+
+// A request asking for the WebSocket upgrade, even a malformed one; those
+// still get 400 from the handshake rather than an HTTP page. A key alone
+// does not make one.
+pub fn is_upgrade(request: &Request) -> bool {
+    request.has_token("Upgrade", "websocket")
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css",
+        Some("js" | "mjs") => "text/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+// Maps the request path below `dir`, refusing anything that could climb out
+// of it; directories serve their index.html.
+fn serve(dir: &Path, path: &str) -> Option<Response> {
+    let mut file = dir.to_path_buf();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        if segment.starts_with('.') || segment.contains('\\') {
+            return None;
+        }
+        file.push(segment);
+    }
+    if file.is_dir() {
+        file.push("index.html");
+    }
+    let body = fs::read(&file).ok()?;
+    Some(
+        Response::new(200)
+            .header("Content-Type", content_type(&file))
+            .body(&body),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process, sync::Arc};
+
+    use super::{is_upgrade, HttpConfig};
+    use crate::handshake::{Request, Response};
+
+    fn get(path: &str) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            headers: vec![],
+        }
+    }
+
+    #[test]
+    fn responds() {
+        let dir = env::temp_dir().join(format!("weso-http-{}", process::id()));
+        fs::create_dir_all(dir.join("app")).unwrap();
+        fs::write(dir.join("index.html"), "<h1>hi</h1>").unwrap();
+        fs::write(dir.join("app/main.js"), "run()").unwrap();
+        fs::write(dir.join(".secret"), "no").unwrap();
+
+        let config = HttpConfig::default();
+        assert_eq!(config.respond(&get("/healthz")).body, b"ok\n");
+        assert_eq!(config.respond(&get("/")).status, 426);
+
+        let config = HttpConfig {
+            static_dir: Some(dir.clone()),
+            responder: Some(Arc::new(|request: &Request| {
+                Response::new(404).body(request.path.as_bytes())
+            })),
+            ..HttpConfig::default()
+        };
+        let index = config.respond(&get("/?v=1"));
+        assert_eq!(
+            (index.status, index.body.as_slice()),
+            (200, &b"<h1>hi</h1>"[..])
+        );
+        let script = config.respond(&get("/app/main.js"));
+        assert_eq!(script.header_value("Content-Type"), Some("text/javascript"));
+        assert_eq!(config.respond(&get("/.secret")).body, b"/.secret");
+        assert_eq!(config.respond(&get("/app/../../etc/passwd")).status, 404);
+        assert_eq!(config.respond(&get("/missing")).status, 404);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut upgrade = get("/chat");
+        upgrade
+            .headers
+            .push(("Sec-WebSocket-Key".into(), "abc".into()));
+        assert!(!is_upgrade(&upgrade));
+        upgrade
+            .headers
+            .push(("Upgrade".into(), "h2c, WebSocket".into()));
+        assert!(is_upgrade(&upgrade));
+    }
+}
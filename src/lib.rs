@@ -0,0 +1,29 @@
+pub mod auth;
+pub mod base64;
+pub mod client;
+pub mod compression;
+mod connection;
+pub mod deflate;
+pub mod digest;
+pub mod extension;
+pub mod frame;
+pub mod handshake;
+pub mod hmac;
+pub mod http;
+pub mod limit;
+pub mod listener;
+pub mod message;
+pub mod mux;
+pub mod origin;
+pub mod pool;
+pub mod pubsub;
+pub mod random;
+mod reactor;
+pub mod router;
+pub mod server;
+pub mod sha1;
+pub mod sha256;
+pub mod signal;
+pub mod stream;
+pub mod timer;
+pub mod token;
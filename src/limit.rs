@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+
+// The longest wait a refused client is told about; a bucket that never
+// refills would otherwise ask for forever.
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl Rate {
+    pub fn new(per_second: f64, burst: f64) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst);
+        self.last = now;
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn retry_after(&self) -> Duration {
+        if self.rate.per_second <= 0.0 {
+            return MAX_RETRY_AFTER;
+        }
+        let seconds = ((1.0 - self.tokens) / self.rate.per_second).max(0.0);
+        Duration::try_from_secs_f64(seconds)
+            .unwrap_or(MAX_RETRY_AFTER)
+            .min(MAX_RETRY_AFTER)
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rate.burst
+    }
+}
+
+pub fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let bits = u128::from(ip) & !((1u128 << 64) - 1);
+            IpAddr::V6(Ipv6Addr::from(bits))
+        }
+    }
+}
+
+const PRUNE_AT: usize = 4096;
+
+#[derive(Debug, Clone, Default)]
+pub struct Limiter {
+    max_total: Option<usize>,
+    max_per_ip: Option<usize>,
+    max_per_subnet: Option<usize>,
+    handshake_rate: Option<Rate>,
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_subnet: HashMap<IpAddr, usize>,
+    buckets: HashMap<IpAddr, TokenBucket>,
+}
+
+impl Limiter {
+    pub fn new(
+        max_total: Option<usize>,
+        max_per_ip: Option<usize>,
+        max_per_subnet: Option<usize>,
+        handshake_rate: Option<Rate>,
+    ) -> Self {
+        Self {
+            max_total,
+            max_per_ip,
+            max_per_subnet,
+            handshake_rate,
+            ..Self::default()
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn count(&self, ip: IpAddr) -> usize {
+        self.per_ip.get(&ip).copied().unwrap_or(0)
+    }
+
+    // On refusal the caller gets back how long the client should wait.
+    pub fn admit(&mut self, ip: IpAddr, now: Instant) -> Result<(), Option<Duration>> {
+        let over = |count: usize, max: Option<usize>| max.is_some_and(|max| count >= max);
+        if over(self.total, self.max_total)
+            || over(self.count(ip), self.max_per_ip)
+            || over(
+                self.per_subnet.get(&subnet(ip)).copied().unwrap_or(0),
+                self.max_per_subnet,
+            )
+        {
+            return Err(None);
+        }
+
+        if let Some(rate) = self.handshake_rate {
+            if self.buckets.len() >= PRUNE_AT {
+                self.buckets.retain(|_, bucket| !bucket.is_full(now));
+            }
+            let bucket = self
+                .buckets
+                .entry(ip)
+                .or_insert_with(|| TokenBucket::new(rate, now));
+            if !bucket.try_take(now) {
+                return Err(Some(bucket.retry_after()));
+            }
+        }
+
+        self.total += 1;
+        *self.per_ip.entry(ip).or_default() += 1;
+        *self.per_subnet.entry(subnet(ip)).or_default() += 1;
+        Ok(())
+    }
+
+    // Local peers (Unix sockets) only count toward the total.
+    pub fn admit_local(&mut self) -> Result<(), Option<Duration>> {
+        if self.max_total.is_some_and(|max| self.total >= max) {
+            return Err(None);
+        }
+        self.total += 1;
+        Ok(())
+    }
+
+    pub fn release_local(&mut self) {
+        self.total = self.total.saturating_sub(1);
+    }
+
+    pub fn release(&mut self, ip: IpAddr) {
+        fn decrement(map: &mut HashMap<IpAddr, usize>, key: IpAddr) {
+            if let Some(count) = map.get_mut(&key) {
+                *count -= 1;
+                if *count == 0 {
+                    map.remove(&key);
+                }
+            }
+        }
+        if self.per_ip.contains_key(&ip) {
+            self.total -= 1;
+            decrement(&mut self.per_ip, ip);
+            decrement(&mut self.per_subnet, subnet(ip));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    use super::{subnet, Limiter, Rate, TokenBucket, MAX_RETRY_AFTER};
+
+    #[test]
+    fn bucket_refills() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Rate::new(10.0, 2.0), now);
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+        assert!(bucket.retry_after() <= Duration::from_millis(100));
+        assert!(bucket.try_take(now + Duration::from_millis(100)));
+
+        let mut burst_only = TokenBucket::new(Rate::new(0.0, 1.0), now);
+        assert!(burst_only.try_take(now));
+        assert!(!burst_only.try_take(now));
+        assert_eq!(burst_only.retry_after(), MAX_RETRY_AFTER);
+        let mut trickle = TokenBucket::new(Rate::new(1e-300, 1.0), now);
+        assert!(trickle.try_take(now));
+        assert_eq!(trickle.retry_after(), MAX_RETRY_AFTER);
+    }
+
+    #[test]
+    fn subnets() {
+        let v4: IpAddr = "10.1.2.3".parse().unwrap();
+        let v6: IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        assert_eq!(subnet(v4), "10.1.2.0".parse::<IpAddr>().unwrap());
+        assert_eq!(subnet(v6), "2001:db8:1:2::".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn per_ip_and_subnet_limits() {
+        let now = Instant::now();
+        let mut limiter = Limiter::new(None, Some(2), Some(3), None);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(limiter.admit(a, now).is_ok());
+        assert!(limiter.admit(a, now).is_ok());
+        assert_eq!(limiter.admit(a, now), Err(None));
+        assert!(limiter.admit(b, now).is_ok());
+        assert_eq!(limiter.admit(b, now), Err(None));
+        limiter.release(a);
+        assert!(limiter.admit(b, now).is_ok());
+        assert_eq!(limiter.total(), 3);
+
+        let mut limiter = Limiter::new(None, None, None, Some(Rate::new(1.0, 1.0)));
+        assert!(limiter.admit(a, now).is_ok());
+        assert!(matches!(limiter.admit(a, now), Err(Some(_))));
+        assert!(limiter.admit(b, now).is_ok());
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, SocketAddr, TcpListener},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::{
+            fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::stream::Transport;
+
+// Numbers the private directories sockets are staged in.
+static STAGED: AtomicUsize = AtomicUsize::new(0);
+
+// Index of a listener in the order it was added to the server or mux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ListenerId(pub usize);
+
+impl fmt::Display for ListenerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "listener {}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(Option<PathBuf>),
+}
+
+impl Address {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Tcp(addr) => Some(addr.ip()),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            Self::Unix(None) => write!(f, "unix:(unnamed)"),
+        }
+    }
+}
+
+// A Unix listener that owns its socket file: a stale file left behind by a
+// dead process is replaced on bind, and the file is removed on drop as long
+// as it is still the one we created.
+#[derive(Debug)]
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    inode: u64,
+}
+
+impl UnixSocket {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::bind_with_mode(path, None)
+    }
+
+    pub fn bind_with_mode<P: AsRef<Path>>(path: P, mode: Option<u32>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+            if UnixStream::connect(&path).is_ok() {
+                return Err(io::ErrorKind::AddrInUse.into());
+            }
+            fs::remove_file(&path)?;
+        }
+        let listener = match mode {
+            Some(mode) => bind_staged(&path, mode)?,
+            None => UnixListener::bind(&path)?,
+        };
+        // Drop never runs for a socket that failed to set up, so the file
+        // it created is removed here.
+        let inode = fs::symlink_metadata(&path)
+            .map(|metadata| metadata.ino())
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&path);
+            })?;
+        Ok(Self {
+            listener,
+            path,
+            inode,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+// Binds in a directory only we can enter, sets the mode and then renames
+// the socket into place, so it is never reachable with wider permissions
+// than asked for. Changing the umask instead would affect every thread.
+fn bind_staged(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let n = STAGED.fetch_add(1, Ordering::Relaxed);
+    let dir = path.with_file_name(format!(".weso-{}-{n}", process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let staged = dir.join("s");
+    let listener = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&dir);
+    listener
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if fs::symlink_metadata(&self.path).is_ok_and(|metadata| metadata.ino() == self.inode) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixSocket),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Self::Tcp(listener)
+    }
+}
+
+impl From<UnixSocket> for Listener {
+    fn from(socket: UnixSocket) -> Self {
+        Self::Unix(socket)
+    }
+}
+
+impl Listener {
+    pub fn accept(&self) -> io::Result<(Box<dyn Transport>, Address)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Box::new(stream), Address::Tcp(addr)))
+            }
+            Self::Unix(socket) => {
+                let (stream, addr) = socket.listener.accept()?;
+                let path = addr.as_pathname().map(Path::to_path_buf);
+                Ok((Box::new(stream), Address::Unix(path)))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<Address> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().map(Address::Tcp),
+            Self::Unix(socket) => Ok(Address::Unix(Some(socket.path.clone()))),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Self::Unix(socket) => socket.listener.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            Self::Unix(socket) => socket.listener.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        os::unix::{
+            fs::PermissionsExt,
+            net::{UnixListener, UnixStream},
+        },
+        process,
+    };
+
+    use super::UnixSocket;
+
+    #[test]
+    fn socket_file_lifecycle() {
+        let path = env::temp_dir().join(format!("weso-listener-{}.sock", process::id()));
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let socket = UnixSocket::bind_with_mode(&path, Some(0o600)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        UnixStream::connect(&path).unwrap();
+        socket.listener.accept().unwrap();
+        assert!(UnixSocket::bind(&path).is_err());
+        drop(socket);
+        assert!(!path.exists());
+
+        fs::write(&path, b"not a socket").unwrap();
+        assert!(UnixSocket::bind(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use std::borrow::Cow;
+
+use crate::{
+    extension::Chain,
+    frame::{apply_mask, Frame, Opcode},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<(u16, String)>),
+}
+
+impl Message {
+    pub fn text(message: &str) -> Self {
+        Self::Text(message.to_string())
+    }
+
+    pub fn binary(message: &[u8]) -> Self {
+        Self::Binary(message.to_vec())
+    }
+
+    pub fn close(status: u16, reason: &str) -> Self {
+        Self::Close(Some((status, reason.to_string())))
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Self::Text(_) => Opcode::Text,
+            Self::Binary(_) => Opcode::Binary,
+            Self::Ping(_) => Opcode::Ping,
+            Self::Pong(_) => Opcode::Pong,
+            Self::Close(_) => Opcode::Close,
+        }
+    }
+
+    pub fn payload(&self) -> Cow<'_, [u8]> {
+        match self {
+            Self::Text(text) => Cow::Borrowed(text.as_bytes()),
+            Self::Binary(data) | Self::Ping(data) | Self::Pong(data) => Cow::Borrowed(data),
+            Self::Close(None) => Cow::Borrowed(&[]),
+            Self::Close(Some((status, reason))) => {
+                let mut payload = status.to_be_bytes().to_vec();
+                payload.extend_from_slice(reason.as_bytes());
+                Cow::Owned(payload)
+            }
+        }
+    }
+
+    pub fn from_parts(opcode: Opcode, payload: Vec<u8>) -> Option<Self> {
+        match opcode {
+            Opcode::Text => String::from_utf8(payload).ok().map(Self::Text),
+            Opcode::Binary => Some(Self::Binary(payload)),
+            Opcode::Ping => Some(Self::Ping(payload)),
+            Opcode::Pong => Some(Self::Pong(payload)),
+            Opcode::Close => match payload.len() {
+                0 => Some(Self::Close(None)),
+                1 => None,
+                _ => {
+                    let status = u16::from_be_bytes([payload[0], payload[1]]);
+                    let reason = String::from_utf8(payload[2..].to_vec()).ok()?;
+                    Some(Self::Close(Some((status, reason))))
+                }
+            },
+            Opcode::Continuation | Opcode::Reserved(_) => None,
+        }
+    }
+
+    pub fn is_control(&self) -> bool {
+        matches!(self, Self::Ping(_) | Self::Pong(_) | Self::Close(_))
+    }
+
+    pub fn encode(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        frame(self.opcode(), &self.payload(), mask, 0)
+    }
+
+    pub fn encode_with(&self, mask: Option<[u8; 4]>, extensions: &mut Chain) -> Vec<u8> {
+        if extensions.is_empty() {
+            return self.encode(mask);
+        }
+        let (rsv, payload) = extensions.encode(self.opcode(), self.payload().into_owned());
+        frame(self.opcode(), &payload, mask, rsv)
+    }
+}
+
+fn frame(opcode: Opcode, payload: &[u8], mask: Option<[u8; 4]>, rsv: u8) -> Vec<u8> {
+    let mut frame = Frame::new(true, opcode, mask, payload.len());
+    frame.rsv = rsv;
+    let header = frame.header();
+    let mut blob = Vec::with_capacity(header.len() + payload.len());
+    blob.extend_from_slice(&header);
+    blob.extend_from_slice(payload);
+    if let Some(mask) = mask {
+        apply_mask(&mut blob[header.len()..], mask, 0);
+    }
+    blob
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Message;
+    use crate::frame::Frame;
+
+    #[test]
+    fn encode_then_parse() {
+        let message = Message::close(1000, "bye");
+        let blob = message.encode(Some([1, 2, 3, 4]));
+        let (frame, header) = Frame::parse(&blob).unwrap().unwrap();
+        let mut payload = blob[header..].to_vec();
+        crate::frame::apply_mask(&mut payload, frame.mask.unwrap(), 0);
+        assert_eq!(Message::from_parts(frame.opcode, payload), Some(message));
+    }
+}
@@ -1,11 +1,21 @@
 use std::{
     ffi::c_int,
-    net::{TcpListener, TcpStream},
-    os::fd::{AsRawFd, FromRawFd, IntoRawFd},
+    fs::File,
+    io::{self, Read, Write},
+    mem::ManuallyDrop,
+    os::fd::{AsRawFd, FromRawFd, RawFd},
+    time::{Duration, Instant},
 };
 
-use crate::stream::WsStream;
+use crate::{
+    listener::{Listener, ListenerId},
+    stream::WsStream,
+    timer::{Expired, TimerId, Timers},
+};
+
+const TIMER_RESOLUTION: Duration = Duration::from_millis(10);
 
+#[derive(Clone, Copy)]
 pub enum Ev {
     POLLNVAL,
     POLLHUP,
@@ -15,9 +25,6 @@ pub enum Ev {
     POLLIN,
 }
 
-const EINTR: isize = 4;
-const TIMEOUT: isize = 0;
-
 #[derive(Debug)]
 pub enum PollErr {
     Interupted,
@@ -49,41 +56,121 @@ extern "C" {
     fn poll(pfds: *const Pollfd, fdcount: usize, timeout: isize) -> isize;
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct Ready {
+    pub fd: RawFd,
+    pub revents: i16,
+}
+
+impl Ready {
+    pub fn is(&self, ev: Ev) -> bool {
+        self.revents & i16::from(ev) != 0
+    }
+
+    pub fn readable(&self) -> bool {
+        self.is(Ev::POLLIN)
+    }
+
+    pub fn writable(&self) -> bool {
+        self.is(Ev::POLLOUT)
+    }
+
+    pub fn hangup(&self) -> bool {
+        self.is(Ev::POLLHUP) || self.is(Ev::POLLERR) || self.is(Ev::POLLNVAL)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Io {
+    Ready(Ready),
+    Expired(Expired),
+}
+
+// A readable descriptor handed out by `Mux::poll`. It borrows the
+// descriptor, so dropping it leaves the registered stream open.
+pub struct Polled(ManuallyDrop<File>);
+
+impl Read for Polled {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Polled {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsRawFd for Polled {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
 pub enum Event<'a> {
-    Join(&'a mut TcpListener),
-    Ready(Vec<WsStream>),
+    Join(ListenerId, &'a mut Listener),
+    Ready(Vec<WsStream<Polled>>),
+    Expired(Vec<Expired>),
 }
 
 pub struct Mux {
     pfds: Vec<Pollfd>,
     //stream_map: HashMap<c_int, WsStream>,
-    listener: TcpListener,
+    listeners: Vec<Listener>,
+    timers: Timers,
+}
+
+impl Default for Mux {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Mux {
-    //pub fn new() -> Self {
-    //    Self {
-    //        pfds: Vec::new(),
-    //        pfd_map: HashMap::new()
-    //    }
-    //}
-
-    pub fn with_listener(stream: TcpListener) -> Self {
+    pub fn new() -> Self {
         Self {
-            pfds: vec![Pollfd {
-                fd: stream.as_raw_fd(),
+            pfds: Vec::new(),
+            listeners: Vec::new(),
+            timers: Timers::new(TIMER_RESOLUTION),
+        }
+    }
+
+    pub fn with_listener<L: Into<Listener>>(listener: L) -> Self {
+        let mut mux = Self::new();
+        mux.add_listener(listener);
+        mux
+    }
+
+    // Listeners occupy the front of `pfds`, in the order they were added.
+    pub fn add_listener<L: Into<Listener>>(&mut self, listener: L) -> ListenerId {
+        let listener = listener.into();
+        let index = self.listeners.len();
+        self.pfds.insert(
+            index,
+            Pollfd {
+                fd: listener.as_raw_fd(),
                 events: Ev::POLLIN.into(),
                 revents: 0,
-            }],
-            //stream_map: HashMap::new(),
-            listener: stream,
-        }
+            },
+        );
+        self.listeners.push(listener);
+        ListenerId(index)
+    }
+
+    fn first(&self) -> usize {
+        self.listeners.len()
     }
 
-    pub fn push_stream(&mut self, stream: TcpStream) {
-        let fd = stream.into_raw_fd();
+    // The caller keeps ownership of the stream and must remove it before
+    // closing it.
+    pub fn push_stream<S: AsRawFd>(&mut self, stream: &S) {
         self.add_pfd(Pollfd {
-            fd,
+            fd: stream.as_raw_fd(),
             events: Ev::POLLIN.into(),
             revents: 0,
         });
@@ -95,41 +182,142 @@ impl Mux {
         self.pfds.push(pfd);
     }
 
-    pub fn remove(&mut self, fd: WsStream) {
-        let mut index = 0;
-        let fd = fd.stream.as_raw_fd();
-        for pfd in &self.pfds {
-            if pfd.fd == fd {
-                break;
+    pub fn listeners(&self) -> &[Listener] {
+        &self.listeners
+    }
+
+    pub fn listener_of(&self, fd: RawFd) -> Option<ListenerId> {
+        self.pfds[..self.first()]
+            .iter()
+            .position(|pfd| pfd.fd == fd)
+            .map(ListenerId)
+    }
+
+    pub fn take_listeners(&mut self) -> Vec<Listener> {
+        self.pfds.drain(..self.first());
+        std::mem::take(&mut self.listeners)
+    }
+
+    pub fn register(&mut self, fd: RawFd, events: i16) {
+        self.add_pfd(Pollfd {
+            fd,
+            events,
+            revents: 0,
+        });
+    }
+
+    pub fn reregister(&mut self, fd: RawFd, events: i16) {
+        let first = self.first();
+        if let Some(pfd) = self.pfds.iter_mut().skip(first).find(|pfd| pfd.fd == fd) {
+            pfd.events = events;
+        }
+    }
+
+    pub fn deregister(&mut self, fd: RawFd) {
+        let first = self.first();
+        if let Some(index) = self.pfds.iter().skip(first).position(|pfd| pfd.fd == fd) {
+            self.pfds.swap_remove(index + first);
+        }
+    }
+
+    pub fn schedule(&mut self, after: Duration, token: u64) -> TimerId {
+        self.timers.schedule(Instant::now(), after, token)
+    }
+
+    pub fn schedule_repeating(&mut self, every: Duration, token: u64) -> TimerId {
+        self.timers.schedule_repeating(Instant::now(), every, token)
+    }
+
+    pub fn cancel(&mut self, timer: TimerId) -> bool {
+        self.timers.cancel(timer)
+    }
+
+    fn timeout(&self, timeout: isize) -> isize {
+        match self.timers.next_timeout(Instant::now()) {
+            Some(next) => {
+                // poll takes an int of milliseconds; farther deadlines wait in steps.
+                let next = next.as_nanos().div_ceil(1_000_000).min(c_int::MAX as u128) as isize;
+                if timeout < 0 {
+                    next
+                } else {
+                    next.min(timeout)
+                }
             }
-            index += 1;
+            None => timeout,
+        }
+    }
+
+    pub fn poll_io(&mut self, timeout: isize) -> Result<Vec<Io>, PollErr> {
+        for pfd in &mut self.pfds {
+            pfd.revents = 0;
+        }
+        let timeout = self.timeout(timeout);
+        let events = unsafe { poll(self.pfds.as_ptr(), self.pfds.len(), timeout) };
+        if events < 0 {
+            return match std::io::Error::last_os_error().kind() {
+                std::io::ErrorKind::Interrupted => Err(PollErr::Interupted),
+                _ => Err(PollErr::Other),
+            };
         }
-        self.pfds.remove(index);
-        unsafe { TcpStream::from_raw_fd(fd) };
+
+        let mut io: Vec<Io> = self
+            .pfds
+            .iter()
+            .filter(|pfd| pfd.revents != 0)
+            .map(|pfd| {
+                Io::Ready(Ready {
+                    fd: pfd.fd,
+                    revents: pfd.revents,
+                })
+            })
+            .collect();
+        io.extend(self.timers.expire(Instant::now()).into_iter().map(Io::Expired));
+        if io.is_empty() {
+            Err(PollErr::TimedOut)
+        } else {
+            Ok(io)
+        }
+    }
+
+    pub fn remove<S: AsRawFd>(&mut self, stream: &S) {
+        self.deregister(stream.as_raw_fd());
         //self.stream_map.remove(&fd.as_raw_fd());
     }
 
-    pub fn poll(&mut self, timeout: isize) -> Result<Event, PollErr> {
+    pub fn poll(&mut self, timeout: isize) -> Result<Event<'_>, PollErr> {
+        let expired = self.timers.expire(Instant::now());
+        if !expired.is_empty() {
+            return Ok(Event::Expired(expired));
+        }
+        let timeout = self.timeout(timeout);
         let events = unsafe { poll(self.pfds.as_ptr(), self.pfds.len(), timeout) };
         if events == 0 {
-            Err(PollErr::TimedOut)
-        } else if events == EINTR {
-            Err(PollErr::Interupted)
+            let expired = self.timers.expire(Instant::now());
+            if expired.is_empty() {
+                Err(PollErr::TimedOut)
+            } else {
+                Ok(Event::Expired(expired))
+            }
         } else if events < 0 {
-            Err(PollErr::Other)
+            match std::io::Error::last_os_error().kind() {
+                std::io::ErrorKind::Interrupted => Err(PollErr::Interupted),
+                _ => Err(PollErr::Other),
+            }
         } else {
             let mut ready = vec![];
             for (i, pfd) in self.pfds.iter().enumerate() {
                 let events = pfd.revents;
-                if i == 0 {
-                    if events & (Ev::POLLIN as i16) != 0 {
-                        return Ok(Event::Join(&mut (self.listener)));
+                if i < self.first() {
+                    if events & i16::from(Ev::POLLIN) != 0 {
+                        return Ok(Event::Join(ListenerId(i), &mut self.listeners[i]));
                     }
                 } else {
-                    if events & (Ev::POLLIN as i16) != 0 {
-                        let mut stream = WsStream::new(unsafe { TcpStream::from_raw_fd(pfd.fd) });
-                        stream.read_frame();
-                        ready.push(stream);
+                    if events & i16::from(Ev::POLLIN) != 0 {
+                        let file = unsafe { File::from_raw_fd(pfd.fd) };
+                        let mut stream = WsStream::new(Polled(ManuallyDrop::new(file)));
+                        if stream.read_frame().is_ok() {
+                            ready.push(stream);
+                        }
                     }
                 }
             }
@@ -0,0 +1,94 @@
+// Which `Origin` headers may open connections. Entries are full origins
+// ("https://app.example.com") or wildcards ("https://*.example.com") that
+// match any subdomain but not the domain itself; an entry without a scheme
+// matches every scheme. Browsers always send Origin on WebSocket requests,
+// so requests without one come from other clients and `allow_missing`
+// decides about them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginPolicy {
+    allowed: Vec<String>,
+    allow_missing: bool,
+}
+
+impl Default for OriginPolicy {
+    fn default() -> Self {
+        Self {
+            allowed: vec![],
+            allow_missing: true,
+        }
+    }
+}
+
+fn split(origin: &str) -> (Option<&str>, &str) {
+    match origin.split_once("://") {
+        Some((scheme, host)) => (Some(scheme), host),
+        None => (None, origin),
+    }
+}
+
+fn matches(pattern: &str, origin: &str) -> bool {
+    let (scheme, host) = split(pattern);
+    let (origin_scheme, origin_host) = split(origin);
+    if scheme.is_some() && scheme != origin_scheme {
+        return false;
+    }
+    match host.strip_prefix("*.") {
+        Some(domain) => origin_host
+            .strip_suffix(domain)
+            .and_then(|sub| sub.strip_suffix('.'))
+            .is_some_and(|sub| !sub.is_empty()),
+        None => host == origin_host,
+    }
+}
+
+impl OriginPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(mut self, origin: &str) -> Self {
+        let origin = origin.trim().trim_end_matches('/');
+        self.allowed.push(origin.to_ascii_lowercase());
+        self
+    }
+
+    pub fn allow_missing(mut self, allow: bool) -> Self {
+        self.allow_missing = allow;
+        self
+    }
+
+    pub fn permits(&self, origin: Option<&str>) -> bool {
+        let origin = match origin {
+            Some(origin) => origin.trim().trim_end_matches('/').to_ascii_lowercase(),
+            None => return self.allow_missing,
+        };
+        self.allowed.iter().any(|pattern| matches(pattern, &origin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OriginPolicy;
+
+    #[test]
+    fn patterns() {
+        let policy = OriginPolicy::new()
+            .allow("https://app.example.com")
+            .allow("https://*.tools.example.com")
+            .allow("*.local:8080");
+        assert!(policy.permits(Some("https://app.example.com")));
+        assert!(policy.permits(Some("HTTPS://App.Example.com/")));
+        assert!(!policy.permits(Some("http://app.example.com")));
+        assert!(!policy.permits(Some("https://app.example.com.evil.net")));
+        assert!(!policy.permits(Some("https://app.example.com:444")));
+        assert!(policy.permits(Some("https://a.tools.example.com")));
+        assert!(policy.permits(Some("https://a.b.tools.example.com")));
+        assert!(!policy.permits(Some("https://tools.example.com")));
+        assert!(!policy.permits(Some("https://eviltools.example.com")));
+        assert!(policy.permits(Some("http://box.local:8080")));
+        assert!(!policy.permits(Some("http://box.local")));
+        assert!(!policy.permits(Some("null")));
+        assert!(policy.permits(None));
+        assert!(!policy.allow_missing(false).permits(None));
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct WorkerPool {
+    sender: mpsc::Sender<Job>,
+    size: usize,
+}
+
+impl WorkerPool {
+    // Panics if `size` is zero, as a pool without workers would take jobs
+    // and never run them.
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "a worker pool needs at least one worker");
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..size {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("weso-worker-{i}"))
+                .spawn(move || loop {
+                    let job = match receiver.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    // A panicking job must not take its worker down with it,
+                    // or jobs would pile up once every worker had died.
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                })
+                .expect("failed to spawn worker");
+        }
+        Self { sender, size }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        let _ = self.sender.send(Box::new(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::WorkerPool;
+
+    #[test]
+    fn runs_jobs() {
+        let pool = WorkerPool::new(3);
+        let (tx, rx) = mpsc::channel();
+        for i in 0..10 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(i * i).unwrap());
+        }
+        let mut results: Vec<i32> = rx.iter().take(10).collect();
+        results.sort();
+        assert_eq!(results, (0..10).map(|i| i * i).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let pool = WorkerPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        pool.execute(|| panic!("job failed"));
+        pool.execute(move || tx.send(()).unwrap());
+        rx.recv().unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_empty_pools() {
+        WorkerPool::new(0);
+    }
+}
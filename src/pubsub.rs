@@ -0,0 +1,112 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::server::ConnectionId;
+
+#[derive(Default)]
+pub struct Rooms {
+    rooms: HashMap<String, BTreeSet<ConnectionId>>,
+    memberships: HashMap<ConnectionId, BTreeSet<String>>,
+}
+
+impl Rooms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connect(&mut self, id: ConnectionId) {
+        self.memberships.entry(id).or_default();
+    }
+
+    pub fn disconnect(&mut self, id: ConnectionId) -> Vec<String> {
+        let rooms = self.memberships.remove(&id).unwrap_or_default();
+        for room in &rooms {
+            self.remove_member(room, id);
+        }
+        rooms.into_iter().collect()
+    }
+
+    pub fn join(&mut self, id: ConnectionId, room: &str) -> bool {
+        match self.memberships.get_mut(&id) {
+            Some(joined) => {
+                joined.insert(room.to_string());
+                self.rooms.entry(room.to_string()).or_default().insert(id)
+            }
+            None => false,
+        }
+    }
+
+    pub fn leave(&mut self, id: ConnectionId, room: &str) -> bool {
+        let removed = self
+            .memberships
+            .get_mut(&id)
+            .is_some_and(|joined| joined.remove(room));
+        if removed {
+            self.remove_member(room, id);
+        }
+        removed
+    }
+
+    fn remove_member(&mut self, room: &str, id: ConnectionId) {
+        if let Some(members) = self.rooms.get_mut(room) {
+            members.remove(&id);
+            if members.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+    }
+
+    pub fn members(&self, room: &str) -> Vec<ConnectionId> {
+        self.rooms
+            .get(room)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn member_count(&self, room: &str) -> usize {
+        self.rooms.get(room).map_or(0, BTreeSet::len)
+    }
+
+    pub fn rooms(&self) -> Vec<String> {
+        let mut rooms: Vec<String> = self.rooms.keys().cloned().collect();
+        rooms.sort();
+        rooms
+    }
+
+    pub fn rooms_of(&self, id: ConnectionId) -> Vec<String> {
+        self.memberships
+            .get(&id)
+            .map(|joined| joined.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rooms;
+    use crate::server::ConnectionId;
+
+    #[test]
+    fn membership() {
+        let mut rooms = Rooms::new();
+        let (a, b) = (ConnectionId(1), ConnectionId(2));
+        assert!(!rooms.join(a, "chat"));
+
+        rooms.connect(a);
+        rooms.connect(b);
+        assert!(rooms.join(a, "chat"));
+        assert!(!rooms.join(a, "chat"));
+        assert!(rooms.join(b, "chat"));
+        assert!(rooms.join(b, "ticks"));
+        assert_eq!(rooms.members("chat"), vec![a, b]);
+        assert_eq!(rooms.member_count("ticks"), 1);
+
+        assert!(rooms.leave(a, "chat"));
+        assert!(!rooms.leave(a, "chat"));
+        assert_eq!(rooms.members("chat"), vec![b]);
+
+        assert_eq!(rooms.disconnect(b), vec!["chat", "ticks"]);
+        assert_eq!(rooms.member_count("chat"), 0);
+        assert!(rooms.rooms().is_empty());
+        assert!(!rooms.join(b, "chat"));
+    }
+}
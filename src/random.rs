@@ -0,0 +1,48 @@
+use std::{
+    collections::hash_map::RandomState,
+    fs::File,
+    hash::{BuildHasher, Hasher},
+    io::Read,
+    sync::OnceLock,
+};
+
+// Opened once and shared; reads from /dev/urandom never block or run short.
+static URANDOM: OnceLock<Option<File>> = OnceLock::new();
+
+// Fills `buf` from the kernel's generator. Should /dev/urandom be missing,
+// as in some chroots, it falls back to the randomly keyed SipHash std seeds
+// its hash maps with, which is still unpredictable to a peer.
+pub fn fill(buf: &mut [u8]) {
+    let urandom = URANDOM.get_or_init(|| File::open("/dev/urandom").ok());
+    if let Some(mut file) = urandom.as_ref() {
+        if file.read_exact(buf).is_ok() {
+            return;
+        }
+    }
+    for chunk in buf.chunks_mut(8) {
+        let word = RandomState::new().build_hasher().finish().to_le_bytes();
+        chunk.copy_from_slice(&word[..chunk.len()]);
+    }
+}
+
+// A fresh masking key for a client frame (RFC 6455 §5.3).
+pub fn mask() -> [u8; 4] {
+    let mut mask = [0u8; 4];
+    fill(&mut mask);
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fill;
+
+    #[test]
+    fn fills_every_byte() {
+        let mut first = [0u8; 37];
+        let mut second = [0u8; 37];
+        fill(&mut first);
+        fill(&mut second);
+        assert_ne!(first, second);
+        assert_ne!(first, [0u8; 37]);
+    }
+}
@@ -0,0 +1,691 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    compression::PerMessageDeflate,
+    connection::Connection,
+    extension::{Chain, Extension},
+    handshake::{Limits, Request, Response},
+    http,
+    limit::TokenBucket,
+    listener::ListenerId,
+    message::Message,
+    mux::{Ev, Io, Mux, PollErr, Ready},
+    server::{ConnectionId, Handler, Peer, ServerHandle},
+    stream::Transport,
+    timer::TimerId,
+};
+
+pub(crate) enum Target {
+    All,
+    One(ConnectionId),
+    Many(Vec<ConnectionId>),
+}
+
+pub(crate) enum Command {
+    Adopt(Box<dyn Transport>, Peer),
+    Send(Target, Arc<[u8]>),
+    Close(ConnectionId, u16, String),
+    Shutdown,
+}
+
+pub(crate) struct Inbox {
+    commands: Mutex<Vec<Command>>,
+    waker: UnixStream,
+    woken: AtomicBool,
+}
+
+impl Inbox {
+    pub fn new() -> io::Result<(Self, UnixStream)> {
+        let (wake_rx, waker) = UnixStream::pair()?;
+        wake_rx.set_nonblocking(true)?;
+        waker.set_nonblocking(true)?;
+        let inbox = Self {
+            commands: Mutex::new(vec![]),
+            waker,
+            woken: AtomicBool::new(false),
+        };
+        Ok((inbox, wake_rx))
+    }
+
+    pub fn push(&self, command: Command) {
+        self.commands.lock().unwrap().push(command);
+        if !self.woken.swap(true, Ordering::AcqRel) {
+            let _ = (&self.waker).write(&[1]);
+        }
+    }
+
+    fn take(&self) -> Vec<Command> {
+        std::mem::take(&mut *self.commands.lock().unwrap())
+    }
+}
+
+fn drain(stream: &UnixStream) {
+    let mut buffer = [0u8; 64];
+    while let Ok(n) = (&*stream).read(&mut buffer) {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Timeout {
+    Drain,
+    Close(ConnectionId),
+    Handshake(RawFd),
+    Reject(RawFd),
+    Keepalive,
+}
+
+struct Pending {
+    peer: Peer,
+    stream: Box<dyn Transport>,
+    buffer: Vec<u8>,
+    timer: TimerId,
+}
+
+// A refusal the socket did not take at once; the rest is written as the
+// peer reads, until the handshake deadline.
+struct Rejected {
+    stream: Box<dyn Transport>,
+    bytes: Vec<u8>,
+    written: usize,
+    timer: TimerId,
+}
+
+// Writes until done or the socket is full; false once the stream is
+// finished with, either way.
+fn write_some(rejected: &mut Rejected) -> bool {
+    while rejected.written < rejected.bytes.len() {
+        match rejected.stream.write(&rejected.bytes[rejected.written..]) {
+            Ok(0) => return false,
+            Ok(n) => rejected.written += n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => return false,
+        }
+    }
+    false
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Drain {
+    Running,
+    Draining,
+    Done,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Role {
+    Worker(usize),
+    Acceptor,
+}
+
+pub(crate) struct Reactor<H> {
+    role: Role,
+    mux: Mux,
+    wake_rx: UnixStream,
+    signals: Option<UnixStream>,
+    handle: ServerHandle,
+    handler: H,
+    pending: HashMap<RawFd, Pending>,
+    rejected: HashMap<RawFd, Rejected>,
+    connections: HashMap<RawFd, Connection>,
+    ids: HashMap<ConnectionId, RawFd>,
+    timeouts: HashMap<TimerId, Timeout>,
+    drain: Drain,
+}
+
+impl<H: Handler> Reactor<H> {
+    pub fn new(
+        role: Role,
+        mut mux: Mux,
+        wake_rx: UnixStream,
+        handle: ServerHandle,
+        handler: H,
+    ) -> Self {
+        mux.register(wake_rx.as_raw_fd(), Ev::POLLIN.into());
+        let mut timeouts = HashMap::new();
+        if let (Role::Worker(_), Some(every)) = (role, handle.config().keepalive) {
+            timeouts.insert(mux.schedule_repeating(every, 0), Timeout::Keepalive);
+        }
+        Self {
+            role,
+            mux,
+            wake_rx,
+            signals: None,
+            handle,
+            handler,
+            pending: HashMap::new(),
+            rejected: HashMap::new(),
+            connections: HashMap::new(),
+            ids: HashMap::new(),
+            timeouts,
+            drain: Drain::Running,
+        }
+    }
+
+    pub fn with_signals(mut self, signals: Option<UnixStream>) -> Self {
+        if let Some(signals) = &signals {
+            self.mux.register(signals.as_raw_fd(), Ev::POLLIN.into());
+        }
+        self.signals = signals;
+        self
+    }
+
+    fn inbox(&self) -> &Inbox {
+        match self.role {
+            Role::Worker(index) => self.handle.inbox(index),
+            Role::Acceptor => self.handle.acceptor_inbox(),
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            match self.drain {
+                Drain::Done => break,
+                Drain::Draining if self.connections.is_empty() && self.pending.is_empty() => break,
+                _ => {}
+            }
+            match self.mux.poll_io(-1) {
+                Ok(io) => {
+                    for io in io {
+                        match io {
+                            Io::Ready(ready) => self.dispatch(ready),
+                            Io::Expired(expired) => {
+                                if let Some(timeout) = self.timeouts.remove(&expired.id) {
+                                    self.expire(expired.id, timeout);
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(PollErr::Interupted) | Err(PollErr::TimedOut) => {}
+                Err(PollErr::Other) => return Err(io::Error::other("poll failed")),
+            }
+            self.drain_commands();
+        }
+
+        let fds: Vec<RawFd> = self.pending.keys().copied().collect();
+        for fd in fds {
+            self.abandon(fd, None);
+        }
+        let fds: Vec<RawFd> = self.rejected.keys().copied().collect();
+        for fd in fds {
+            self.drop_rejected(fd);
+        }
+        let fds: Vec<RawFd> = self.connections.keys().copied().collect();
+        for fd in fds {
+            self.remove(fd);
+        }
+        Ok(())
+    }
+
+    fn schedule(&mut self, after: Duration, timeout: Timeout) -> TimerId {
+        let timer = self.mux.schedule(after, 0);
+        self.timeouts.insert(timer, timeout);
+        timer
+    }
+
+    fn expire(&mut self, timer: TimerId, timeout: Timeout) {
+        match timeout {
+            Timeout::Drain => self.drain = Drain::Done,
+            Timeout::Close(id) => {
+                if let Some(fd) = self.ids.get(&id).copied() {
+                    self.remove(fd);
+                }
+            }
+            Timeout::Handshake(fd) => self.abandon(fd, Some(Response::new(408))),
+            Timeout::Reject(fd) => {
+                if self.rejected.remove(&fd).is_some() {
+                    self.mux.deregister(fd);
+                }
+            }
+            Timeout::Keepalive => {
+                self.timeouts.insert(timer, timeout);
+                self.keepalive();
+            }
+        }
+    }
+
+    // Pings every connection; one that stayed silent since the previous
+    // ping is closed instead.
+    fn keepalive(&mut self) {
+        let ping: Arc<[u8]> = Message::Ping(vec![]).encode(None).into();
+        let fds: Vec<RawFd> = self.connections.keys().copied().collect();
+        for fd in fds {
+            let conn = self.connections.get_mut(&fd).unwrap();
+            if conn.awaiting_pong {
+                self.close(fd, 1001, "keepalive timeout");
+            } else {
+                conn.awaiting_pong = true;
+                conn.enqueue(ping.clone());
+            }
+            self.flush(fd);
+        }
+    }
+
+    fn close(&mut self, fd: RawFd, status: u16, reason: &str) {
+        let conn = match self.connections.get_mut(&fd) {
+            Some(conn) if !conn.close_sent => conn,
+            _ => return,
+        };
+        conn.enqueue_close(status, reason);
+        let id = conn.id;
+        let after = self.handle.config().close_timeout;
+        let timer = self.schedule(after, Timeout::Close(id));
+        self.connections.get_mut(&fd).unwrap().close_timer = Some(timer);
+    }
+
+    fn shutdown(&mut self) {
+        if self.drain != Drain::Running {
+            return;
+        }
+        self.mux.take_listeners();
+        self.drain = Drain::Draining;
+        self.schedule(self.handle.config().drain_timeout, Timeout::Drain);
+        let fds: Vec<RawFd> = self.pending.keys().copied().collect();
+        for fd in fds {
+            self.abandon(fd, Some(Response::new(503)));
+        }
+        let fds: Vec<RawFd> = self.connections.keys().copied().collect();
+        for fd in fds {
+            self.connections
+                .get_mut(&fd)
+                .unwrap()
+                .enqueue_close(1001, "Going Away");
+            self.flush(fd);
+        }
+    }
+
+    fn dispatch(&mut self, ready: Ready) {
+        if let Some(listener) = self.mux.listener_of(ready.fd) {
+            self.accept(listener);
+        } else if ready.fd == self.wake_rx.as_raw_fd() {
+            drain(&self.wake_rx);
+            self.inbox().woken.store(false, Ordering::Release);
+        } else if Some(ready.fd) == self.signals.as_ref().map(|signals| signals.as_raw_fd()) {
+            drain(self.signals.as_ref().unwrap());
+            self.handle.shutdown();
+        } else if self.pending.contains_key(&ready.fd) {
+            self.handshake(ready.fd);
+        } else if let Some(rejected) = self.rejected.get_mut(&ready.fd) {
+            if ready.hangup() || !write_some(rejected) {
+                self.drop_rejected(ready.fd);
+            }
+        } else if ready.readable() {
+            self.read(ready.fd);
+        } else if ready.writable() {
+            self.flush(ready.fd);
+        } else if ready.hangup() {
+            self.remove(ready.fd);
+        }
+    }
+
+    fn accept(&mut self, listener: ListenerId) {
+        loop {
+            let (stream, addr) = match self.mux.listeners()[listener.0].accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+            if !self.handle.begin_handshake() {
+                self.reject(stream, self.unavailable(None));
+                continue;
+            }
+            if let Err(retry) = self.handle.admit(&addr) {
+                self.handle.end_handshake();
+                self.reject(stream, self.unavailable(retry));
+                continue;
+            }
+            let peer = Peer {
+                id: self.handle.next_id(),
+                addr,
+                listener,
+                protocol: None,
+                request: Request::default(),
+                params: vec![],
+                principal: None,
+            };
+            match self.role {
+                Role::Worker(_) => self.adopt(stream, peer),
+                Role::Acceptor => self
+                    .handle
+                    .inbox_of(peer.id)
+                    .push(Command::Adopt(stream, peer)),
+            }
+        }
+    }
+
+    fn unavailable(&self, retry: Option<Duration>) -> Response {
+        let retry = retry.unwrap_or(self.handle.config().retry_after);
+        let seconds = retry
+            .as_secs()
+            .saturating_add((retry.subsec_nanos() > 0) as u64);
+        Response::new(503).header("Retry-After", &seconds.max(1).to_string())
+    }
+
+    fn adopt(&mut self, stream: Box<dyn Transport>, peer: Peer) {
+        if self.drain != Drain::Running || stream.set_nonblocking(true).is_err() {
+            self.reject(stream, self.unavailable(None));
+            self.handle.end_handshake();
+            self.handle.release(&peer.addr);
+            return;
+        }
+        let fd = stream.as_raw_fd();
+        self.mux.register(fd, Ev::POLLIN.into());
+        let timer = self.schedule(
+            self.handle.config().handshake_timeout,
+            Timeout::Handshake(fd),
+        );
+        self.pending.insert(
+            fd,
+            Pending {
+                peer,
+                stream,
+                buffer: vec![],
+                timer,
+            },
+        );
+    }
+
+    fn take_pending(&mut self, fd: RawFd) -> Option<Pending> {
+        let pending = self.pending.remove(&fd)?;
+        self.mux.cancel(pending.timer);
+        self.timeouts.remove(&pending.timer);
+        self.handle.end_handshake();
+        Some(pending)
+    }
+
+    fn abandon(&mut self, fd: RawFd, response: Option<Response>) {
+        if let Some(pending) = self.take_pending(fd) {
+            self.mux.deregister(fd);
+            self.handle.release(&pending.peer.addr);
+            if let Some(response) = response {
+                self.reject(pending.stream, response);
+            }
+        }
+    }
+
+    // Writes the response without blocking. A socket that does not take it
+    // all is polled for writability until the handshake deadline; past
+    // max_pending_handshakes such sockets are dropped instead.
+    fn reject(&mut self, mut stream: Box<dyn Transport>, response: Response) {
+        if stream.set_nonblocking(true).is_err() {
+            return;
+        }
+        let bytes = response.to_bytes();
+        let written = match stream.write(&bytes) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => 0,
+            Err(_) => return,
+        };
+        let config = self.handle.config();
+        if written == bytes.len() || self.rejected.len() >= config.max_pending_handshakes {
+            return;
+        }
+        let fd = stream.as_raw_fd();
+        let timer = self.schedule(config.handshake_timeout, Timeout::Reject(fd));
+        self.mux.register(fd, Ev::POLLOUT.into());
+        self.rejected.insert(
+            fd,
+            Rejected {
+                stream,
+                bytes,
+                written,
+                timer,
+            },
+        );
+    }
+
+    fn drop_rejected(&mut self, fd: RawFd) {
+        if let Some(rejected) = self.rejected.remove(&fd) {
+            self.mux.cancel(rejected.timer);
+            self.timeouts.remove(&rejected.timer);
+            self.mux.deregister(fd);
+        }
+    }
+
+    fn handshake(&mut self, fd: RawFd) {
+        let config = self.handle.config();
+        let limits = Limits {
+            max_size: config.max_header_size,
+            max_headers: config.max_headers,
+        };
+        let pending = self.pending.get_mut(&fd).unwrap();
+        let mut chunk = [0u8; 1024];
+        while pending.buffer.len() < limits.max_size {
+            match pending.stream.read(&mut chunk) {
+                Ok(0) => return self.abandon(fd, None),
+                Ok(n) => pending.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return self.abandon(fd, None),
+            }
+        }
+
+        match Request::parse(&pending.buffer, &limits) {
+            Ok(None) => {}
+            Ok(Some((request, used))) => self.upgrade(fd, request, used),
+            Err(response) => self.abandon(fd, Some(response)),
+        }
+    }
+
+    fn upgrade(&mut self, fd: RawFd, request: Request, used: usize) {
+        if !http::is_upgrade(&request) {
+            let response = self.handle.config().http.respond(&request);
+            return self.abandon(fd, Some(response));
+        }
+        if let Err(response) = request.validate() {
+            return self.abandon(fd, Some(response));
+        }
+        if let Some(origins) = &self.handle.config().origins {
+            if !origins.permits(request.header("Origin")) {
+                return self.abandon(fd, Some(Response::new(403)));
+            }
+        }
+        let principal = match &self.handle.config().auth {
+            Some(auth) => match auth.authenticate(&request) {
+                Ok(principal) => Some(principal),
+                Err(response) => return self.abandon(fd, Some(response)),
+            },
+            None => None,
+        };
+        let pending = self.pending.get_mut(&fd).unwrap();
+        pending.peer.request = request;
+        pending.peer.principal = principal;
+        if let Err(response) = self.handler.on_handshake(&self.handle, &mut pending.peer) {
+            return self.abandon(fd, Some(response));
+        }
+        let mut pending = self.take_pending(fd).unwrap();
+        let request = std::mem::take(&mut pending.peer.request);
+        let key = request.key().unwrap();
+        let config = self.handle.config();
+        let mut conn = Connection::new(pending.peer.id, pending.peer.addr.clone(), pending.stream);
+        conn.incoming.buffer = pending.buffer[used..].to_vec();
+        conn.incoming.max_message_size = config.max_message_size;
+        conn.bucket = config
+            .message_rate
+            .map(|rate| TokenBucket::new(rate, Instant::now()));
+        let mut response = Response::switching_protocols(key);
+        let offered = request.header_values("Sec-WebSocket-Protocol").join(",");
+        let offered: Vec<&str> = offered.split(',').map(str::trim).collect();
+        pending.peer.protocol = config
+            .protocols
+            .iter()
+            .find(|protocol| offered.contains(&protocol.as_str()))
+            .cloned();
+        if let Some(protocol) = &pending.peer.protocol {
+            response = response.header("Sec-WebSocket-Protocol", protocol);
+        }
+        let offers = request.header_values("Sec-WebSocket-Extensions").join(", ");
+        if !offers.is_empty() {
+            let mut candidates = config.extensions.instantiate();
+            if let Some(deflate) = config.deflate {
+                let deflate: Box<dyn Extension> = Box::new(PerMessageDeflate::new(deflate));
+                candidates.insert(0, deflate);
+            }
+            let (extensions, accepted) = Chain::accept(candidates, &offers);
+            if let Some(accepted) = accepted {
+                response = response.header("Sec-WebSocket-Extensions", &accepted);
+            }
+            conn.extensions = extensions;
+        }
+        conn.enqueue(response.to_bytes().into());
+
+        self.connections.insert(fd, conn);
+        self.ids.insert(pending.peer.id, fd);
+        self.handle.room_table().connect(pending.peer.id);
+        pending.peer.request = request;
+        self.handler.on_open(&self.handle, &pending.peer);
+        self.read(fd);
+    }
+
+    fn read(&mut self, fd: RawFd) {
+        let conn = match self.connections.get_mut(&fd) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let open = matches!(conn.fill(), Ok(true));
+        conn.awaiting_pong = false;
+
+        let mut messages = vec![];
+        let mut limited = false;
+        loop {
+            let message = conn.next_message();
+            if let (Ok(Some(data)), Some(bucket)) = (&message, conn.bucket.as_mut()) {
+                if !data.is_control() && !bucket.try_take(Instant::now()) {
+                    limited = true;
+                    continue;
+                }
+            }
+            match message {
+                Ok(Some(Message::Close(payload))) => {
+                    conn.close_received = true;
+                    if !conn.close_sent {
+                        match &payload {
+                            Some((status, reason)) => conn.enqueue_close(*status, reason),
+                            None => conn.enqueue_close(1000, ""),
+                        }
+                    }
+                    messages.push(Message::Close(payload));
+                    break;
+                }
+                Ok(Some(Message::Ping(payload))) => {
+                    conn.enqueue(Message::Pong(payload.clone()).encode(None).into());
+                    messages.push(Message::Ping(payload));
+                }
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => break,
+                Err(status) => {
+                    conn.enqueue_close(status, "");
+                    conn.close_received = true;
+                    break;
+                }
+            }
+        }
+        let id = conn.id;
+
+        for message in messages {
+            self.handler.on_message(&self.handle, id, message);
+        }
+        if limited {
+            self.close(fd, 1008, "rate limit exceeded");
+        }
+        if open {
+            self.flush(fd);
+        } else {
+            self.remove(fd);
+        }
+    }
+
+    fn flush(&mut self, fd: RawFd) {
+        let conn = match self.connections.get_mut(&fd) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if conn.flush().is_err() || conn.done() {
+            self.remove(fd);
+            return;
+        }
+        let events = if conn.outbound.is_empty() {
+            Ev::POLLIN.into()
+        } else {
+            i16::from(Ev::POLLIN) | i16::from(Ev::POLLOUT)
+        };
+        self.mux.reregister(fd, events);
+    }
+
+    fn remove(&mut self, fd: RawFd) {
+        if let Some(conn) = self.connections.remove(&fd) {
+            if let Some(timer) = conn.close_timer {
+                self.mux.cancel(timer);
+                self.timeouts.remove(&timer);
+            }
+            self.mux.deregister(fd);
+            self.ids.remove(&conn.id);
+            self.handle.release(&conn.addr);
+            self.handler.on_close(&self.handle, conn.id);
+            self.handle.room_table().disconnect(conn.id);
+        }
+    }
+
+    fn drain_commands(&mut self) {
+        let mut touched = vec![];
+        for command in self.inbox().take() {
+            match command {
+                Command::Adopt(stream, peer) => self.adopt(stream, peer),
+                Command::Shutdown => match self.role {
+                    Role::Worker(_) => self.shutdown(),
+                    Role::Acceptor => {
+                        self.mux.take_listeners();
+                        self.drain = Drain::Done;
+                    }
+                },
+                Command::Send(Target::All, blob) => {
+                    for (fd, conn) in self.connections.iter_mut() {
+                        conn.send(blob.clone());
+                        touched.push(*fd);
+                    }
+                }
+                Command::Send(Target::One(id), blob) => {
+                    if let Some(fd) = self.ids.get(&id) {
+                        self.connections.get_mut(fd).unwrap().send(blob);
+                        touched.push(*fd);
+                    }
+                }
+                Command::Send(Target::Many(ids), blob) => {
+                    for id in ids {
+                        if let Some(fd) = self.ids.get(&id) {
+                            self.connections.get_mut(fd).unwrap().send(blob.clone());
+                            touched.push(*fd);
+                        }
+                    }
+                }
+                Command::Close(id, status, reason) => {
+                    if let Some(fd) = self.ids.get(&id).copied() {
+                        self.close(fd, status, &reason);
+                        touched.push(fd);
+                    }
+                }
+            }
+        }
+        touched.sort_unstable();
+        touched.dedup();
+        for fd in touched {
+            self.flush(fd);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+
+use crate::{
+    handshake::Response,
+    message::Message,
+    server::{ConnectionId, Handler, Peer, ServerHandle},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+// A route path: "/chat" matches exactly, "/static/*" matches the prefix and
+// captures the rest as "*", and "/rooms/:id" captures the segment as "id".
+// Empty segments are ignored, so trailing slashes do not matter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+    prefix: bool,
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Self {
+        let mut segments: Vec<&str> = segments(pattern).collect();
+        let prefix = segments.last() == Some(&"*");
+        if prefix {
+            segments.pop();
+        }
+        let segments = segments
+            .into_iter()
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Literal(segment.to_string()),
+            })
+            .collect();
+        Self { segments, prefix }
+    }
+
+    // The captured params when `path` (query string included or not) matches.
+    pub fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let path = path.split('?').next().unwrap_or_default();
+        let mut parts = segments(path);
+        let mut params = vec![];
+        for segment in &self.segments {
+            let part = parts.next()?;
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => params.push((name.clone(), part.to_string())),
+            }
+        }
+        let rest: Vec<&str> = parts.collect();
+        if self.prefix {
+            params.push(("*".to_string(), rest.join("/")));
+        } else if !rest.is_empty() {
+            return None;
+        }
+        Some(params)
+    }
+}
+
+trait Route: Handler + Send {
+    fn boxed(&self) -> Box<dyn Route>;
+}
+
+impl<H: Handler + Clone + Send + 'static> Route for H {
+    fn boxed(&self) -> Box<dyn Route> {
+        Box::new(self.clone())
+    }
+}
+
+// Dispatches each connection to the handler of the first route matching its
+// request path; requests matching no route are refused with 404 before the
+// upgrade. Routes are tried in the order they were added.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<(Pattern, Box<dyn Route>)>,
+    connections: HashMap<ConnectionId, usize>,
+}
+
+impl Clone for Router {
+    fn clone(&self) -> Self {
+        Self {
+            routes: self
+                .routes
+                .iter()
+                .map(|(pattern, handler)| (pattern.clone(), handler.boxed()))
+                .collect(),
+            connections: HashMap::new(),
+        }
+    }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<H: Handler + Clone + Send + 'static>(mut self, pattern: &str, handler: H) -> Self {
+        self.routes
+            .push((Pattern::parse(pattern), Box::new(handler)));
+        self
+    }
+
+    fn handler(&mut self, id: ConnectionId) -> Option<&mut Box<dyn Route>> {
+        let index = *self.connections.get(&id)?;
+        Some(&mut self.routes[index].1)
+    }
+}
+
+impl Handler for Router {
+    fn on_handshake(&mut self, server: &ServerHandle, peer: &mut Peer) -> Result<(), Response> {
+        let (index, params) = self
+            .routes
+            .iter()
+            .enumerate()
+            .find_map(|(index, (pattern, _))| Some((index, pattern.matches(&peer.request.path)?)))
+            .ok_or_else(|| Response::new(404))?;
+        peer.params = params;
+        self.routes[index].1.on_handshake(server, peer)?;
+        self.connections.insert(peer.id, index);
+        Ok(())
+    }
+
+    fn on_open(&mut self, server: &ServerHandle, peer: &Peer) {
+        if let Some(handler) = self.handler(peer.id) {
+            handler.on_open(server, peer);
+        }
+    }
+
+    fn on_message(&mut self, server: &ServerHandle, id: ConnectionId, message: Message) {
+        if let Some(handler) = self.handler(id) {
+            handler.on_message(server, id, message);
+        }
+    }
+
+    fn on_close(&mut self, server: &ServerHandle, id: ConnectionId) {
+        if let Some(handler) = self.handler(id) {
+            handler.on_close(server, id);
+        }
+        self.connections.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread};
+
+    use super::{Pattern, Router};
+    use crate::{
+        client::Client,
+        message::Message,
+        server::{ConnectionId, Handler, Peer, Server, ServerHandle},
+    };
+
+    fn params(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        Pattern::parse(pattern).matches(path)
+    }
+
+    fn pair(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(params("/chat", "/chat"), Some(vec![]));
+        assert_eq!(params("/chat", "/chat/?x=1"), Some(vec![]));
+        assert_eq!(params("/chat", "/chat/room"), None);
+        assert_eq!(params("/chat", "/"), None);
+        assert_eq!(params("/", "/"), Some(vec![]));
+        assert_eq!(
+            params("/rooms/:id/users/:user", "/rooms/7/users/ann"),
+            Some(vec![pair("id", "7"), pair("user", "ann")])
+        );
+        assert_eq!(params("/rooms/:id", "/rooms"), None);
+        assert_eq!(
+            params("/static/*", "/static/css/site.css"),
+            Some(vec![pair("*", "css/site.css")])
+        );
+        assert_eq!(params("/static/*", "/static"), Some(vec![pair("*", "")]));
+        assert_eq!(params("/static/*", "/other"), None);
+    }
+
+    #[derive(Clone)]
+    struct Tagged(&'static str, mpsc::Sender<(&'static str, Option<String>)>);
+
+    impl Handler for Tagged {
+        fn on_open(&mut self, _server: &ServerHandle, peer: &Peer) {
+            let room = peer.param("room").map(str::to_string);
+            self.1.send((self.0, room)).unwrap();
+        }
+
+        fn on_message(&mut self, server: &ServerHandle, id: ConnectionId, message: Message) {
+            if !message.is_control() {
+                server.send_to(id, Message::text(self.0));
+            }
+        }
+    }
+
+    #[test]
+    fn routes_by_path() {
+        let (tx, rx) = mpsc::channel();
+        let router = Router::new()
+            .with("/chat/:room", Tagged("chat", tx.clone()))
+            .with("/events/*", Tagged("events", tx));
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run(router));
+
+        let mut chat = Client::connect(&format!("ws://{addr}/chat/lobby")).unwrap();
+        assert_eq!(rx.recv().unwrap(), ("chat", Some("lobby".to_string())));
+        chat.send(&Message::text("hi")).unwrap();
+        assert_eq!(chat.recv().unwrap(), Message::text("chat"));
+
+        let mut events = Client::connect(&format!("ws://{addr}/events/a/b")).unwrap();
+        assert_eq!(rx.recv().unwrap(), ("events", None));
+        events.send(&Message::text("hi")).unwrap();
+        assert_eq!(events.recv().unwrap(), Message::text("events"));
+
+        let refused = Client::connect(&format!("ws://{addr}/chat")).err().unwrap();
+        assert!(refused.to_string().contains("404"));
+    }
+}
@@ -1,83 +1,853 @@
 use std::{
-    io::{BufReader, Read},
-    net::TcpListener,
+    ffi::c_int,
+    fmt, io,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    os::unix::net::UnixStream,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::Duration,
 };
 
-use frame::Opcode;
-use handshake::new_connection;
-use mux::Mux;
-
-mod base64;
-mod frame;
-mod mux;
-mod sha1;
-mod stream;
-mod handshake;
-
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:3000").unwrap();
-    let mut mux = Mux::with_listener(listener);
-
-    //let server_fd = listener.as_raw_fd();
-    loop {
-        match mux.poll(-1) {
-            Ok(event) => {
-                match event {
-                    mux::Event::Join(listener) => match new_connection(&listener) {
-                        Ok(stream) => {
-                            mux.push_stream(stream);
-                        }
-                        Err(e) => {
-                            panic!("{}", e);
-                        }
-                    },
-                    mux::Event::Ready(ready_streams) => {
-                        for mut stream in ready_streams.into_iter() {
-                            //stream.read_frame();
-                            //let mut reader = BufReader::new(&mut stream);
-                            match stream.opcode() {
-                                Opcode::Continuation => {
-                                    println!("continue from: {:?}", stream.peer_addr());
-                                    //sleep(Duration::from_secs(60));
-                                }
-                                Opcode::Text => {
-                                    println!("text from: {:?}", stream.peer_addr());
-                                    let mut str = String::new();
-                                    let n = stream.read_to_string(&mut str).expect("failed to read");
-                                    println!("message:[{n}] {}", str);
-                                    if str == "kwenda senji" {
-                                        stream.text("nkwende nkwile ku", None).expect("failed to echo");
-                                    }
-                                }
-                                Opcode::Binary => {
-                                    println!("binary from: {:?}", stream.peer_addr());
-                                    let mut buf: Vec<u8> = vec![];
-                                    let mut reader = BufReader::new(&mut stream);
-                                    reader.read_to_end(&mut buf).expect("failed to read to end");
-                                }
-                                Opcode::Reserved => {}
-                                Opcode::Close => {
-                                    println!("close from: {:?}", stream.peer_addr());
-                                    stream.bye(None).unwrap();
-                                    mux.remove(stream);
-                                    continue;
-                                }
-                                Opcode::Ping => {
-                                    println!("ping from: {:?}", stream.peer_addr());
-                                    stream.pong(None);
-                                }
-                                Opcode::Pong => {
-                                    continue;
-                                }
-                            }
-                        }
-                    }
-                }
+use crate::{
+    auth::Auth,
+    compression::DeflateConfig,
+    extension::Extensions,
+    handshake::{Request, Response},
+    http::HttpConfig,
+    limit::{Limiter, Rate},
+    listener::{Address, Listener, ListenerId, UnixSocket},
+    message::Message,
+    mux::Mux,
+    origin::OriginPolicy,
+    pool::WorkerPool,
+    pubsub::Rooms,
+    reactor::{Command, Inbox, Reactor, Role, Target},
+    signal,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(pub u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub id: ConnectionId,
+    pub addr: Address,
+    pub listener: ListenerId,
+    pub protocol: Option<String>,
+    pub request: Request,
+    pub params: Vec<(String, String)>,
+    pub principal: Option<String>,
+}
+
+impl Peer {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// `on_handshake` sees the parsed request before the upgrade and may refuse
+// it with an HTTP response. Close frames from the peer reach `on_message`
+// after the reactor has already answered them; `on_close` follows once the
+// connection is gone.
+pub trait Handler {
+    fn on_handshake(&mut self, _server: &ServerHandle, _peer: &mut Peer) -> Result<(), Response> {
+        Ok(())
+    }
+    fn on_open(&mut self, _server: &ServerHandle, _peer: &Peer) {}
+    fn on_message(&mut self, server: &ServerHandle, id: ConnectionId, message: Message);
+    fn on_close(&mut self, _server: &ServerHandle, _id: ConnectionId) {}
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub reactors: usize,
+    pub workers: usize,
+    pub drain_timeout: Duration,
+    pub close_timeout: Duration,
+    pub handshake_timeout: Duration,
+    pub max_header_size: usize,
+    pub max_headers: usize,
+    pub max_pending_handshakes: usize,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_connections_per_subnet: Option<usize>,
+    pub handshake_rate: Option<Rate>,
+    pub message_rate: Option<Rate>,
+    pub max_message_size: Option<usize>,
+    pub keepalive: Option<Duration>,
+    pub protocols: Vec<String>,
+    pub origins: Option<OriginPolicy>,
+    pub auth: Option<Auth>,
+    pub retry_after: Duration,
+    pub deflate: Option<DeflateConfig>,
+    pub extensions: Extensions,
+    pub http: HttpConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            reactors: 1,
+            workers: 0,
+            drain_timeout: Duration::from_secs(5),
+            close_timeout: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(10),
+            max_header_size: 8192,
+            max_headers: 64,
+            max_pending_handshakes: 1024,
+            max_connections: None,
+            max_connections_per_ip: None,
+            max_connections_per_subnet: None,
+            handshake_rate: None,
+            message_rate: None,
+            max_message_size: None,
+            keepalive: None,
+            protocols: vec![],
+            origins: None,
+            auth: None,
+            retry_after: Duration::from_secs(5),
+            deflate: None,
+            extensions: Extensions::default(),
+            http: HttpConfig::default(),
+        }
+    }
+}
+
+struct Shared {
+    config: Config,
+    inboxes: Vec<Inbox>,
+    acceptor: Inbox,
+    stopping: AtomicBool,
+    handshakes: AtomicUsize,
+    limiter: Mutex<Limiter>,
+    rooms: Mutex<Rooms>,
+    next_id: AtomicU64,
+    workers: Option<WorkerPool>,
+}
+
+#[derive(Clone)]
+pub struct ServerHandle {
+    shared: Arc<Shared>,
+}
+
+impl ServerHandle {
+    pub fn broadcast(&self, message: Message) {
+        let blob: Arc<[u8]> = message.encode(None).into();
+        for inbox in &self.shared.inboxes {
+            inbox.push(Command::Send(Target::All, blob.clone()));
+        }
+    }
+
+    pub fn send_to(&self, id: ConnectionId, message: Message) {
+        self.inbox_of(id)
+            .push(Command::Send(Target::One(id), message.encode(None).into()));
+    }
+
+    pub fn close(&self, id: ConnectionId, status: u16, reason: &str) {
+        self.inbox_of(id)
+            .push(Command::Close(id, status, reason.to_string()));
+    }
+
+    pub fn join(&self, id: ConnectionId, room: &str) -> bool {
+        self.room_table().join(id, room)
+    }
+
+    pub fn leave(&self, id: ConnectionId, room: &str) -> bool {
+        self.room_table().leave(id, room)
+    }
+
+    pub fn publish(&self, room: &str, message: Message) {
+        let members = self.members(room);
+        if members.is_empty() {
+            return;
+        }
+        let blob: Arc<[u8]> = message.encode(None).into();
+        let mut groups = vec![vec![]; self.shared.inboxes.len()];
+        for id in members {
+            groups[self.reactor_of(id)].push(id);
+        }
+        for (index, ids) in groups.into_iter().enumerate() {
+            if !ids.is_empty() {
+                self.inbox(index)
+                    .push(Command::Send(Target::Many(ids), blob.clone()));
             }
-            Err(e) => {
-                panic!("{:?}", e);
+        }
+    }
+
+    pub fn members(&self, room: &str) -> Vec<ConnectionId> {
+        self.room_table().members(room)
+    }
+
+    pub fn member_count(&self, room: &str) -> usize {
+        self.room_table().member_count(room)
+    }
+
+    pub fn rooms(&self) -> Vec<String> {
+        self.room_table().rooms()
+    }
+
+    pub fn rooms_of(&self, id: ConnectionId) -> Vec<String> {
+        self.room_table().rooms_of(id)
+    }
+
+    pub fn shutdown(&self) {
+        if self.shared.stopping.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        for inbox in &self.shared.inboxes {
+            inbox.push(Command::Shutdown);
+        }
+        self.shared.acceptor.push(Command::Shutdown);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shared.stopping.load(Ordering::Acquire)
+    }
+
+    // Runs on the worker pool when one is configured, inline otherwise.
+    pub fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        match &self.shared.workers {
+            Some(workers) => workers.execute(job),
+            None => job(),
+        }
+    }
+
+    pub(crate) fn room_table(&self) -> MutexGuard<'_, Rooms> {
+        self.shared.rooms.lock().unwrap()
+    }
+
+    pub(crate) fn next_id(&self) -> ConnectionId {
+        ConnectionId(self.shared.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub(crate) fn begin_handshake(&self) -> bool {
+        let max = self.shared.config.max_pending_handshakes;
+        self.shared
+            .handshakes
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .is_ok()
+    }
+
+    pub(crate) fn end_handshake(&self) {
+        self.shared.handshakes.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    pub(crate) fn admit(&self, addr: &Address) -> Result<(), Option<Duration>> {
+        let mut limiter = self.shared.limiter.lock().unwrap();
+        match addr.ip() {
+            Some(ip) => limiter.admit(ip, std::time::Instant::now()),
+            None => limiter.admit_local(),
+        }
+    }
+
+    pub(crate) fn release(&self, addr: &Address) {
+        let mut limiter = self.shared.limiter.lock().unwrap();
+        match addr.ip() {
+            Some(ip) => limiter.release(ip),
+            None => limiter.release_local(),
+        }
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.shared.limiter.lock().unwrap().total()
+    }
+
+    pub(crate) fn config(&self) -> &Config {
+        &self.shared.config
+    }
+
+    pub(crate) fn inbox(&self, index: usize) -> &Inbox {
+        &self.shared.inboxes[index]
+    }
+
+    pub(crate) fn acceptor_inbox(&self) -> &Inbox {
+        &self.shared.acceptor
+    }
+
+    fn reactor_of(&self, id: ConnectionId) -> usize {
+        (id.0 % self.shared.inboxes.len() as u64) as usize
+    }
+
+    pub(crate) fn inbox_of(&self, id: ConnectionId) -> &Inbox {
+        self.inbox(self.reactor_of(id))
+    }
+}
+
+pub struct Server {
+    listeners: Vec<Listener>,
+    handle: ServerHandle,
+    wake_rxs: Vec<UnixStream>,
+    acceptor_rx: UnixStream,
+    signals: Option<UnixStream>,
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Self::with_listener(TcpListener::bind(addr)?)
+    }
+
+    // Binds a Unix socket, replacing a stale socket file; the file is
+    // removed again when the server stops.
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::with_listener(UnixSocket::bind(path)?)
+    }
+
+    pub fn with_listener<L: Into<Listener>>(listener: L) -> io::Result<Self> {
+        Self::with_config(listener, Config::default())
+    }
+
+    pub fn with_config<L: Into<Listener>>(listener: L, config: Config) -> io::Result<Self> {
+        let mut inboxes = vec![];
+        let mut wake_rxs = vec![];
+        for _ in 0..config.reactors.max(1) {
+            let (inbox, wake_rx) = Inbox::new()?;
+            inboxes.push(inbox);
+            wake_rxs.push(wake_rx);
+        }
+        let (acceptor, acceptor_rx) = Inbox::new()?;
+
+        Ok(Self {
+            listeners: vec![listener.into()],
+            handle: ServerHandle {
+                shared: Arc::new(Shared {
+                    inboxes,
+                    acceptor,
+                    stopping: AtomicBool::new(false),
+                    handshakes: AtomicUsize::new(0),
+                    limiter: Mutex::new(Limiter::new(
+                        config.max_connections,
+                        config.max_connections_per_ip,
+                        config.max_connections_per_subnet,
+                        config.handshake_rate,
+                    )),
+                    rooms: Mutex::new(Rooms::new()),
+                    next_id: AtomicU64::new(0),
+                    workers: (config.workers > 0).then(|| WorkerPool::new(config.workers)),
+                    config,
+                }),
+            },
+            wake_rxs,
+            acceptor_rx,
+            signals: None,
+        })
+    }
+
+    pub fn shutdown_on(&mut self, signals: &[c_int]) -> io::Result<()> {
+        self.signals = Some(signal::self_pipe(signals)?);
+        Ok(())
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    // Connections accepted on the new listener report its id in `Peer`.
+    pub fn add_listener<L: Into<Listener>>(&mut self, listener: L) -> ListenerId {
+        self.listeners.push(listener.into());
+        ListenerId(self.listeners.len() - 1)
+    }
+
+    // The address of the first TCP listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        for listener in &self.listeners {
+            if let Address::Tcp(addr) = listener.local_addr()? {
+                return Ok(addr);
             }
         }
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn listeners(&self) -> &[Listener] {
+        &self.listeners
+    }
+
+    pub fn run<H: Handler + Clone + Send + 'static>(self, handler: H) -> io::Result<()> {
+        let mut mux = Mux::new();
+        for listener in self.listeners {
+            listener.set_nonblocking(true)?;
+            mux.add_listener(listener);
+        }
+        if self.wake_rxs.len() == 1 {
+            let wake_rx = self.wake_rxs.into_iter().next().unwrap();
+            return Reactor::new(Role::Worker(0), mux, wake_rx, self.handle, handler)
+                .with_signals(self.signals)
+                .run();
+        }
+
+        let mut threads = vec![];
+        for (index, wake_rx) in self.wake_rxs.into_iter().enumerate() {
+            let handle = self.handle.clone();
+            let handler = handler.clone();
+            threads.push(
+                thread::Builder::new()
+                    .name(format!("weso-reactor-{index}"))
+                    .spawn(move || {
+                        Reactor::new(Role::Worker(index), Mux::new(), wake_rx, handle, handler)
+                            .run()
+                    })?,
+            );
+        }
+
+        let result = Reactor::new(Role::Acceptor, mux, self.acceptor_rx, self.handle, handler)
+            .with_signals(self.signals)
+            .run();
+        for thread in threads {
+            thread
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("reactor panicked")))?;
+        }
+        result
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, BufRead, BufReader, Read, Write},
+        net::{SocketAddr, TcpStream},
+        sync::{mpsc, Arc},
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    };
+
+    use super::{Config, ConnectionId, Handler, Peer, Server, ServerHandle};
+    use crate::{
+        auth::{Auth, Credentials},
+        client::{Client, ClientConfig},
+        frame::Frame,
+        handshake::{Request, Response},
+        http::HttpConfig,
+        limit::Rate,
+        listener::{Address, ListenerId, UnixSocket},
+        message::Message,
+        origin::OriginPolicy,
+    };
+
+    #[derive(Clone)]
+    struct Opened(mpsc::Sender<ConnectionId>);
+
+    impl Handler for Opened {
+        fn on_open(&mut self, _server: &ServerHandle, peer: &Peer) {
+            self.0.send(peer.id).unwrap();
+        }
+
+        fn on_message(&mut self, _server: &ServerHandle, _id: ConnectionId, _message: Message) {}
+    }
+
+    const UPGRADE: &[u8] = b"GET / HTTP/1.1\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n";
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(UPGRADE).unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        stream
+    }
+
+    fn read_message(stream: &mut TcpStream) -> Message {
+        let frame = Frame::read_frame(stream).unwrap();
+        let mut payload = vec![0u8; frame.payload_length];
+        stream.read_exact(&mut payload).unwrap();
+        Message::from_parts(frame.opcode, payload).unwrap()
+    }
+
+    // Runs `server` on its own thread.
+    fn run_server<H: Handler + Clone + Send + 'static>(
+        server: Server,
+        handler: H,
+    ) -> (SocketAddr, ServerHandle, JoinHandle<io::Result<()>>) {
+        let addr = server.local_addr().unwrap();
+        let handle = server.handle();
+        (addr, handle, thread::spawn(move || server.run(handler)))
+    }
+
+    // Serves `config` on a loopback port, reporting each opened connection.
+    fn spawn_server(config: Config) -> (SocketAddr, ServerHandle, mpsc::Receiver<ConnectionId>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let server = Server::with_config(listener, config).unwrap();
+        let (addr, handle, _) = run_server(server, Opened(tx));
+        (addr, handle, rx)
+    }
+
+    fn broadcast_and_send_to(config: Config) {
+        let (addr, handle, rx) = spawn_server(config);
+
+        let mut a = connect(addr);
+        let first = rx.recv().unwrap();
+        let mut b = connect(addr);
+        rx.recv().unwrap();
+
+        handle.broadcast(Message::text("update"));
+        assert_eq!(read_message(&mut a), Message::text("update"));
+        assert_eq!(read_message(&mut b), Message::text("update"));
+
+        handle.send_to(first, Message::binary(&[1, 2, 3]));
+        handle.broadcast(Message::text("next"));
+        assert_eq!(read_message(&mut a), Message::binary(&[1, 2, 3]));
+        assert_eq!(read_message(&mut a), Message::text("next"));
+        assert_eq!(read_message(&mut b), Message::text("next"));
+    }
+
+    #[test]
+    fn single_reactor() {
+        broadcast_and_send_to(Config::default());
+    }
+
+    #[test]
+    fn multiple_reactors() {
+        broadcast_and_send_to(Config {
+            reactors: 3,
+            ..Config::default()
+        });
+    }
+
+    #[test]
+    fn shutdown_sends_going_away() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = Config {
+            drain_timeout: Duration::from_millis(200),
+            ..Config::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        let server = Server::with_config(listener, config).unwrap();
+        let (addr, handle, running) = run_server(server, Opened(tx));
+
+        let mut polite = connect(addr);
+        rx.recv().unwrap();
+        let mut silent = connect(addr);
+        rx.recv().unwrap();
+
+        let started = Instant::now();
+        handle.shutdown();
+        assert_eq!(
+            read_message(&mut polite),
+            Message::close(1001, "Going Away")
+        );
+        assert_eq!(
+            read_message(&mut silent),
+            Message::close(1001, "Going Away")
+        );
+        polite
+            .write_all(&Message::close(1001, "").encode(Some([1, 2, 3, 4])))
+            .unwrap();
+
+        running.join().unwrap().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn close_timeout_drops_silent_peer() {
+        let (addr, handle, rx) = spawn_server(Config {
+            close_timeout: Duration::from_millis(100),
+            ..Config::default()
+        });
+
+        let mut silent = connect(addr);
+        let id = rx.recv().unwrap();
+        let started = Instant::now();
+        handle.close(id, 1000, "bye");
+        assert_eq!(read_message(&mut silent), Message::close(1000, "bye"));
+        assert_eq!(silent.read(&mut [0u8; 1]).unwrap(), 0);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    fn read_response(stream: &mut TcpStream) -> String {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn plain_http_requests() {
+        let (addr, _handle, _rx) = spawn_server(Config {
+            http: HttpConfig {
+                responder: Some(Arc::new(|request: &Request| {
+                    Response::new(200).body(request.method.as_bytes())
+                })),
+                ..HttpConfig::default()
+            },
+            ..Config::default()
+        });
+
+        let request = |raw: &[u8]| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(raw).unwrap();
+            read_response(&mut stream)
+        };
+        let health = request(b"GET /healthz HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(health.starts_with("HTTP/1.1 200 OK\r\n") && health.ends_with("ok\n"));
+        let other = request(b"POST /submit HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+        assert!(other.ends_with("\r\n\r\nPOST"));
+        let broken = request(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n");
+        assert!(broken.starts_with("HTTP/1.1 400 "));
+    }
+
+    #[test]
+    fn invalid_upgrades_are_refused() {
+        let (addr, _handle, _rx) = spawn_server(Config::default());
+
+        let request = |raw: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(raw.as_bytes()).unwrap();
+            read_response(&mut stream)
+        };
+        let key = "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+        let post = request(&format!(
+            "POST / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Version: 13\r\n{key}\r\n"
+        ));
+        assert!(post.starts_with("HTTP/1.1 400 "));
+        let no_connection = request(&format!(
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\n{key}\r\n"
+        ));
+        assert!(no_connection.starts_with("HTTP/1.1 400 "));
+        for version in ["", "Sec-WebSocket-Version: 8\r\n"] {
+            let old = request(&format!(
+                "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n{version}{key}\r\n"
+            ));
+            assert!(old.starts_with("HTTP/1.1 426 "));
+            assert!(old.contains("\r\nSec-WebSocket-Version: 13\r\n"));
+        }
+    }
+
+    #[test]
+    fn large_responses_finish_in_the_reactor() {
+        let body = Arc::new(vec![b'x'; 32 << 20]);
+        let (addr, _handle, rx) = spawn_server(Config {
+            handshake_timeout: Duration::from_secs(1),
+            http: HttpConfig {
+                responder: Some(Arc::new(move |_: &Request| Response::new(200).body(&body))),
+                ..HttpConfig::default()
+            },
+            ..Config::default()
+        });
+
+        let mut reader = TcpStream::connect(addr).unwrap();
+        reader.write_all(b"GET /big HTTP/1.1\r\n\r\n").unwrap();
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.write_all(b"GET /big HTTP/1.1\r\n\r\n").unwrap();
+        connect(addr);
+        rx.recv().unwrap();
+
+        let mut response = vec![];
+        reader.read_to_end(&mut response).unwrap();
+        assert!(response.ends_with(&[b'x'; 1024]) && response.len() > 32 << 20);
+
+        thread::sleep(Duration::from_millis(1200));
+        let mut truncated = vec![];
+        let _ = stalled.read_to_end(&mut truncated);
+        assert!(truncated.len() < 32 << 20);
+    }
+
+    #[test]
+    fn origin_policy() {
+        let (addr, _handle, rx) = spawn_server(Config {
+            origins: Some(OriginPolicy::new().allow("https://*.example.com")),
+            ..Config::default()
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nOrigin: https://evil.test\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: a\r\n\r\n",
+            )
+            .unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 403 "));
+
+        let url = format!("ws://{addr}/");
+        let config = ClientConfig {
+            headers: vec![("Origin".into(), "https://app.example.com".into())],
+            ..ClientConfig::default()
+        };
+        Client::connect_with(&url, &config).unwrap();
+        Client::connect(&url).unwrap();
+        rx.recv().unwrap();
+        rx.recv().unwrap();
+    }
+
+    #[test]
+    fn authenticated_principal() {
+        #[derive(Clone)]
+        struct Principal(mpsc::Sender<Option<String>>);
+
+        impl Handler for Principal {
+            fn on_open(&mut self, _server: &ServerHandle, peer: &Peer) {
+                self.0.send(peer.principal.clone()).unwrap();
+            }
+
+            fn on_message(&mut self, _: &ServerHandle, _: ConnectionId, _: Message) {}
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let auth = Auth::new("test", |credentials| match credentials {
+            Credentials::Bearer(token) if token == "secret" => Some("robot".to_string()),
+            _ => None,
+        });
+        let config = Config {
+            auth: Some(auth),
+            ..Config::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        let server = Server::with_config(listener, config).unwrap();
+        let (addr, _handle, _) = run_server(server, Principal(tx));
+
+        let refused = Client::connect(&format!("ws://{addr}/")).err().unwrap();
+        assert!(refused.to_string().contains("401"));
+        Client::connect(&format!("ws://{addr}/?token=secret")).unwrap();
+        assert_eq!(rx.recv().unwrap().as_deref(), Some("robot"));
+    }
+
+    #[test]
+    fn silent_handshake_does_not_block_others() {
+        let (addr, handle, rx) = spawn_server(Config {
+            handshake_timeout: Duration::from_millis(150),
+            ..Config::default()
+        });
+
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n").unwrap();
+        let mut fast = connect(addr);
+        rx.recv().unwrap();
+        handle.broadcast(Message::text("hi"));
+        assert_eq!(read_message(&mut fast), Message::text("hi"));
+
+        assert!(read_response(&mut slow).starts_with("HTTP/1.1 408"));
+    }
+
+    #[test]
+    fn oversized_and_excess_handshakes_are_rejected() {
+        let (addr, _handle, _rx) = spawn_server(Config {
+            max_header_size: 256,
+            max_pending_handshakes: 1,
+            ..Config::default()
+        });
+
+        let mut huge = TcpStream::connect(addr).unwrap();
+        huge.write_all(&[b'a'; 512]).unwrap();
+        assert!(read_response(&mut huge).starts_with("HTTP/1.1 431"));
+
+        let _idle = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let mut excess = TcpStream::connect(addr).unwrap();
+        assert!(read_response(&mut excess).starts_with("HTTP/1.1 503"));
+    }
+
+    #[test]
+    fn connection_and_message_limits() {
+        let (addr, _handle, rx) = spawn_server(Config {
+            max_connections_per_ip: Some(1),
+            message_rate: Some(Rate::new(1.0, 2.0)),
+            ..Config::default()
+        });
+
+        let mut first = connect(addr);
+        rx.recv().unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        let response = read_response(&mut second);
+        assert!(response.starts_with("HTTP/1.1 503"));
+        assert!(response.contains("Retry-After: "));
+
+        for _ in 0..3 {
+            first
+                .write_all(&Message::text("spam").encode(Some([1, 2, 3, 4])))
+                .unwrap();
+        }
+        assert_eq!(
+            read_message(&mut first),
+            Message::close(1008, "rate limit exceeded")
+        );
+    }
+
+    #[test]
+    fn multiple_listeners() {
+        #[derive(Clone)]
+        struct Origin(mpsc::Sender<(ListenerId, Address)>);
+
+        impl Handler for Origin {
+            fn on_open(&mut self, _server: &ServerHandle, peer: &Peer) {
+                self.0.send((peer.listener, peer.addr.clone())).unwrap();
+            }
+
+            fn on_message(&mut self, _: &ServerHandle, _: ConnectionId, _: Message) {}
+        }
+
+        let public = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let admin = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let admin_addr = admin.local_addr().unwrap();
+        let path = std::env::temp_dir().join(format!("weso-server-{}.sock", std::process::id()));
+        let config = Config {
+            reactors: 2,
+            drain_timeout: Duration::from_millis(100),
+            ..Config::default()
+        };
+        let mut server = Server::with_config(public, config).unwrap();
+        assert_eq!(server.add_listener(admin), ListenerId(1));
+        let local = server.add_listener(UnixSocket::bind(&path).unwrap());
+        let (tx, rx) = mpsc::channel();
+        let (addr, handle, running) = run_server(server, Origin(tx));
+
+        let _admin = connect(admin_addr);
+        assert_eq!(rx.recv().unwrap().0, ListenerId(1));
+        let _public = connect(addr);
+        assert_eq!(rx.recv().unwrap().0, ListenerId(0));
+        let _local = Client::connect_unix(&format!("ws+unix://{}", path.display())).unwrap();
+        assert_eq!(rx.recv().unwrap(), (local, Address::Unix(None)));
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn keepalive_protocols_and_size_limit() {
+        let (addr, _handle, _rx) = spawn_server(Config {
+            max_message_size: Some(16),
+            keepalive: Some(Duration::from_millis(50)),
+            protocols: vec!["v2.chat".into(), "v1.chat".into()],
+            ..Config::default()
+        });
+
+        let config = ClientConfig {
+            protocols: vec!["v1.chat".into(), "v2.chat".into()],
+            ..ClientConfig::default()
+        };
+        let mut client = Client::connect_with(&format!("ws://{addr}/"), &config).unwrap();
+        assert_eq!(client.protocol(), Some("v2.chat"));
+        assert_eq!(client.recv().unwrap(), Message::Ping(vec![]));
+        client.send(&Message::text(&"x".repeat(17))).unwrap();
+        assert_eq!(client.recv().unwrap(), Message::close(1009, ""));
+
+        let mut silent = connect(addr);
+        assert_eq!(read_message(&mut silent), Message::Ping(vec![]));
+        assert_eq!(
+            read_message(&mut silent),
+            Message::close(1001, "keepalive timeout")
+        );
+    }
+}
@@ -1,272 +1,235 @@
-//#[allow(unused_assignments)]
-enum Sha1 {
-    Success,
-    Null,
-    InputTooLong,
-    Stateerror,
-}
+use std::io;
+
+use crate::digest::Digest;
+
+pub const SHA1_HASH_SIZE: usize = 20;
+pub const SHA1_BLOCK_SIZE: usize = 64;
 
-const SHA1_HASH_SIZE: usize = 20;
+const INITIAL_HASH: [u32; SHA1_HASH_SIZE / 4] =
+    [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
 
+// Incremental SHA-1 (RFC 3174).
+#[derive(Clone)]
 pub struct Sha1Ctx {
     intermediate_hash: [u32; SHA1_HASH_SIZE / 4],
-    low_length: u32,
-    high_length: u32,
-    message_block_index: u16,
-    message_block: [u8; 64],
-    corrupted: bool,
-    computed: bool,
+    length: u64,
+    message_block_index: usize,
+    message_block: [u8; SHA1_BLOCK_SIZE],
 }
 
-macro_rules! sha1_circular_shift {
-    ($bits: expr, $word: expr) => {
-        ($word << $bits) | ($word >> (32 - $bits))
-    };
+impl Default for Sha1Ctx {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Sha1Ctx {
     pub fn new() -> Self {
         Self {
-            low_length: 0,
-            high_length: 0,
+            intermediate_hash: INITIAL_HASH,
+            length: 0,
             message_block_index: 0,
-            computed: false,
-            corrupted: false,
-            intermediate_hash: [0x67452301, 0xeFcdAb89, 0x98bAdcFe, 0x10325476, 0xc3d2e1F0],
-            message_block: [0; 64],
-        }
-    }
-    fn reset(&mut self) {
-        *self = Self {
-            low_length: 0,
-            high_length: 0,
-            message_block_index: 0,
-            computed: false,
-            corrupted: false,
-            intermediate_hash: [0x67452301, 0xeFcdAb89, 0x98bAdcFe, 0x10325476, 0xc3d2e1F0],
-            message_block: [0; 64],
+            message_block: [0; SHA1_BLOCK_SIZE],
         }
     }
 
-    pub fn digest(&mut self, message: &str) -> [u8; SHA1_HASH_SIZE] {
-        self.input(message);
-        self.result()
-    }
-
-    fn input(&mut self, message: &str) {
-        self.reset();
-        let mut bytes = message.bytes();
-        while let Some(byte) = bytes.next() {
-            //if self.corrupted {break;};
-            self.message_block[self.message_block_index as usize] = byte & 0xFF;
-            self.low_length = self.low_length.wrapping_add(8);
-            if self.low_length == 0 {
-                self.high_length = self.high_length.wrapping_add(1);
-                if self.high_length == 0 {
-                    panic!("message too long");
-                }
-            }
-
-            self.message_block_index += 1;
-            if self.message_block_index == 64 {
-                self.process_block();
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length = self.length.wrapping_add(data.len() as u64);
+        if self.message_block_index > 0 {
+            let index = self.message_block_index;
+            let take = (SHA1_BLOCK_SIZE - index).min(data.len());
+            self.message_block[index..index + take].copy_from_slice(&data[..take]);
+            self.message_block_index += take;
+            data = &data[take..];
+            if self.message_block_index < SHA1_BLOCK_SIZE {
+                return;
             }
+            compress(&mut self.intermediate_hash, &self.message_block);
+            self.message_block_index = 0;
         }
-    }
-
-    fn result(&mut self) -> [u8; SHA1_HASH_SIZE] {
-        if self.corrupted {
-            panic!("I am corrupted");
+        let mut blocks = data.chunks_exact(SHA1_BLOCK_SIZE);
+        for block in &mut blocks {
+            compress(&mut self.intermediate_hash, block.try_into().unwrap());
         }
+        let rest = blocks.remainder();
+        self.message_block[..rest.len()].copy_from_slice(rest);
+        self.message_block_index = rest.len();
+    }
 
-        if !self.computed {
-            self.pad_message();
-
-            for i in 0..64 {
-                self.message_block[i] = 0;
-            }
-
-            self.low_length = 0;
-            self.high_length = 0;
-            self.computed = true;
+    pub fn finalize(mut self) -> [u8; SHA1_HASH_SIZE] {
+        let bits = self.length.wrapping_mul(8);
+        let index = self.message_block_index;
+        self.message_block[index] = 0x80;
+        self.message_block[index + 1..].fill(0);
+        if index > 55 {
+            compress(&mut self.intermediate_hash, &self.message_block);
+            self.message_block.fill(0);
         }
+        self.message_block[56..].copy_from_slice(&bits.to_be_bytes());
+        compress(&mut self.intermediate_hash, &self.message_block);
 
-        let mut digest = [0 as u8; SHA1_HASH_SIZE];
-        for i in 0..SHA1_HASH_SIZE {
-            digest[i] = (self.intermediate_hash[i >> 2] >> 8 * (3 - (i & 0x03))) as u8;
+        let mut digest = [0u8; SHA1_HASH_SIZE];
+        for (chunk, word) in digest.chunks_exact_mut(4).zip(self.intermediate_hash) {
+            chunk.copy_from_slice(&word.to_be_bytes());
         }
         digest
     }
+}
 
-    fn pad_message(&mut self) {
-        if self.message_block_index > 55 {
-            self.message_block[self.message_block_index as usize] = 0x80;
-            self.message_block_index += 1;
-            while self.message_block_index < 64 {
-                self.message_block[self.message_block_index as usize] = 0;
-                self.message_block_index += 1;
-            }
-            self.process_block();
-            while self.message_block_index < 56 {
-                self.message_block[self.message_block_index as usize] = 0;
-                self.message_block_index += 1;
-            }
-        } else {
-            self.message_block[self.message_block_index as usize] = 0x80;
-            self.message_block_index += 1;
-            while self.message_block_index < 56 {
-                self.message_block[self.message_block_index as usize] = 0;
-                self.message_block_index += 1;
-            }
+macro_rules! rounds {
+    ($w:expr, $a:ident, $b:ident, $c:ident, $d:ident, $e:ident, $k:expr, $f:expr) => {
+        for word in $w {
+            let temp = $a
+                .rotate_left(5)
+                .wrapping_add($f)
+                .wrapping_add($e)
+                .wrapping_add(*word)
+                .wrapping_add($k);
+            $e = $d;
+            $d = $c;
+            $c = $b.rotate_left(30);
+            $b = $a;
+            $a = temp;
         }
+    };
+}
 
-        self.message_block[56] = (self.high_length >> 24) as u8;
-        self.message_block[57] = (self.high_length >> 16) as u8;
-        self.message_block[58] = (self.high_length >> 8) as u8;
-        self.message_block[59] = self.high_length as u8;
-        self.message_block[60] = (self.low_length >> 24) as u8;
-        self.message_block[61] = (self.low_length >> 16) as u8;
-        self.message_block[62] = (self.low_length >> 8) as u8;
-        self.message_block[63] = (self.low_length) as u8;
-        self.process_block();
+// One 64-byte block, read as big-endian words rather than byte by byte.
+fn compress(state: &mut [u32; SHA1_HASH_SIZE / 4], block: &[u8; SHA1_BLOCK_SIZE]) {
+    let mut w = [0u32; 80];
+    for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_be_bytes(bytes.try_into().unwrap());
+    }
+    for t in 16..80 {
+        w[t] = (w[t - 3] ^ w[t - 8] ^ w[t - 14] ^ w[t - 16]).rotate_left(1);
     }
 
-    fn process_block(&mut self) {
-        let k = [0x5A827999 as u32, 0x6ed9ebA1, 0x8F1bbcdc, 0xcA62c1d6];
-        let mut w = [0 as u32; 80];
-        //let (A, b, c, d, e): (u32, u32, u32, u32, u32);
-
-        for t in 0..16 {
-            w[t] = (self.message_block[t * 4] as u32) << 24;
-            w[t] |= (self.message_block[t * 4 + 1] as u32) << 16;
-            w[t] |= (self.message_block[t * 4 + 2] as u32) << 8;
-            w[t] |= self.message_block[t * 4 + 3] as u32;
-        }
-
-        for t in 16..80 {
-            w[t] = sha1_circular_shift!(1, w[t - 3] ^ w[t - 8] ^ w[t - 14] ^ w[t - 16]);
-        }
-
-        let (mut a, mut b, mut c, mut d, mut e) = (
-            self.intermediate_hash[0],
-            self.intermediate_hash[1],
-            self.intermediate_hash[2],
-            self.intermediate_hash[3],
-            self.intermediate_hash[4],
-        );
-
-        let mut temp = 0;
-
-        for t in 0..20 {
-            temp = sha1_circular_shift!(5, a)
-                .wrapping_add((b & c) | ((!b) & d))
-                .wrapping_add(e)
-                .wrapping_add(w[t])
-                .wrapping_add(k[0]);
-            e = d;
-            d = c;
-            c = sha1_circular_shift!(30, b);
-            b = a;
-            a = temp;
-        }
-
-        for t in 20..40 {
-            temp = sha1_circular_shift!(5, a)
-                .wrapping_add(b ^ c ^ d)
-                .wrapping_add(e)
-                .wrapping_add(w[t])
-                .wrapping_add(k[1]);
-            e = d;
-            d = c;
-            c = sha1_circular_shift!(30, b);
-            b = a;
-            a = temp;
-        }
-
-        for t in 40..60 {
-            temp = sha1_circular_shift!(5, a)
-                .wrapping_add((b & c) | (b & d) | (c & d))
-                .wrapping_add(e)
-                .wrapping_add(w[t])
-                .wrapping_add(k[2]);
-            e = d;
-            d = c;
-            c = sha1_circular_shift!(30, b);
-            b = a;
-            a = temp;
-        }
+    let [mut a, mut b, mut c, mut d, mut e] = *state;
+    rounds!(&w[0..20], a, b, c, d, e, 0x5a827999, (b & c) | (!b & d));
+    rounds!(&w[20..40], a, b, c, d, e, 0x6ed9eba1, b ^ c ^ d);
+    rounds!(
+        &w[40..60],
+        a,
+        b,
+        c,
+        d,
+        e,
+        0x8f1bbcdc,
+        (b & c) | (b & d) | (c & d)
+    );
+    rounds!(&w[60..80], a, b, c, d, e, 0xca62c1d6, b ^ c ^ d);
+
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e]) {
+        *word = word.wrapping_add(value);
+    }
+}
 
-        for t in 60..80 {
-            temp = sha1_circular_shift!(5, a)
-                .wrapping_add(b ^ c ^ d)
-                .wrapping_add(e)
-                .wrapping_add(w[t])
-                .wrapping_add(k[3]);
-            e = d;
-            d = c;
-            c = sha1_circular_shift!(30, b);
-            b = a;
-            a = temp;
-        }
+impl Digest for Sha1Ctx {
+    const BLOCK_SIZE: usize = SHA1_BLOCK_SIZE;
+    const OUTPUT_SIZE: usize = SHA1_HASH_SIZE;
+    type Output = [u8; SHA1_HASH_SIZE];
 
-        self.intermediate_hash[0] = self.intermediate_hash[0].wrapping_add(a);
-        self.intermediate_hash[1] = self.intermediate_hash[1].wrapping_add(b);
-        self.intermediate_hash[2] = self.intermediate_hash[2].wrapping_add(c);
-        self.intermediate_hash[3] = self.intermediate_hash[3].wrapping_add(d);
-        self.intermediate_hash[4] = self.intermediate_hash[4].wrapping_add(e);
+    fn update(&mut self, data: &[u8]) {
+        Sha1Ctx::update(self, data);
+    }
 
-        self.message_block_index = 0;
+    fn finalize(self) -> Self::Output {
+        Sha1Ctx::finalize(self)
     }
 }
 
+impl io::Write for Sha1Ctx {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
 
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use std::io::{self, Read};
+
     use super::Sha1Ctx;
+    use crate::digest::Digest;
+
+    fn hex(digest: [u8; 20]) -> String {
+        digest.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
 
+    fn repeated(input: &str, count: usize) -> [u8; 20] {
+        let mut ctx = Sha1Ctx::new();
+        for _ in 0..count {
+            ctx.update(input.as_bytes());
+        }
+        ctx.finalize()
+    }
+
+    // The four tests of RFC 3174 section 7.3.
     #[test]
-    fn shash() {
-        let inputs = [
-            "abc",
-            "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
-            "a",
-            "0123456701234567012345670123456701234567012345670123456701234567",
-            "abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
-            ""
-        ];
+    fn rfc_3174() {
+        assert_eq!(
+            hex(repeated("abc", 1)),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex(repeated(
+                "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                1
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(
+            hex(repeated("a", 1_000_000)),
+            "34aa973cd4c4daa4f61eeb2bdbad27316534016f"
+        );
+        assert_eq!(
+            hex(repeated(
+                "0123456701234567012345670123456701234567012345670123456701234567",
+                10
+            )),
+            "dea356a2cddd90c7a7ecedc5ebb563934f460452"
+        );
+    }
 
-        let outputs = [
-            [
-                0xA9u8, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6A, 0xbA, 0x3e, 0x25, 0x71, 0x78,
-                0x50, 0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d,
-            ],
-            [
-                0x84u8, 0x98, 0x3e, 0x44, 0x1c, 0x3b, 0xd2, 0x6e, 0xbA, 0xAe, 0x4A, 0xA1, 0xF9,
-                0x51, 0x29, 0xe5, 0xe5, 0x46, 0x70, 0xF1,
-            ],
-            [
-                134u8, 247, 228, 55, 250, 165, 167, 252, 225, 93, 29, 220, 185, 234, 234, 234, 55,
-                118, 103, 184,
-            ],
-            [
-                224u8, 192, 148, 232, 103, 239, 70, 195, 80, 239, 84, 167, 245, 157, 214, 11, 237,
-                146, 174, 131,
-            ],
-            [
-                0xa4u8, 0x9b, 0x24, 0x46, 0xa0, 0x2c, 0x64, 0x5b, 0xf4, 0x19, 0xf9, 0x95, 0xb6,
-                0x70, 0x91, 0x25, 0x3a, 0x04, 0xa2, 0x59,
-            ],
-            [
-                0xdau8, 0x39, 0xa3, 0xee, 0x5e, 0x6b, 0x4b, 0x0d, 0x32, 0x55, 0xbf, 0xef, 0x95,
-                0x60, 0x18, 0x90, 0xaf, 0xd8, 0x07, 0x09,
-            ],
+    #[test]
+    fn block_boundaries() {
+        let cases = [
+            ("", "da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+            ("a", "86f7e437faa5a7fce15d1ddcb9eaeaea377667b8"),
+            (
+                "0123456701234567012345670123456701234567012345670123456701234567",
+                "e0c094e867ef46c350ef54a7f59dd60bed92ae83",
+            ),
+            (
+                "abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+                "a49b2446a02c645bf419f995b67091253a04a259",
+            ),
         ];
+        for (input, expected) in cases {
+            assert_eq!(hex(Sha1Ctx::digest(input.as_bytes())), expected);
+        }
 
-        let mut ctx = Sha1Ctx::new();
-
-        for i in 0..inputs.len() {
-            assert_eq!(ctx.digest(inputs[i]), outputs[i]);
+        let data: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        let whole = Sha1Ctx::digest(&data);
+        for split in [1, 55, 56, 63, 64, 65, 999] {
+            let mut ctx = Sha1Ctx::new();
+            ctx.update(&data[..split]);
+            ctx.update(&data[split..]);
+            assert_eq!(ctx.finalize(), whole);
         }
     }
+
+    #[test]
+    fn hashes_readers() {
+        let mut ctx = Sha1Ctx::new();
+        io::copy(&mut io::repeat(b'a').take(1_000_000), &mut ctx).unwrap();
+        assert_eq!(
+            hex(ctx.finalize()),
+            "34aa973cd4c4daa4f61eeb2bdbad27316534016f"
+        );
+    }
 }
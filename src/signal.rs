@@ -0,0 +1,42 @@
+use std::{
+    ffi::c_int,
+    io,
+    os::{fd::IntoRawFd, unix::net::UnixStream},
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+pub const SIGINT: c_int = 2;
+pub const SIGTERM: c_int = 15;
+
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" {
+    fn signal(signum: c_int, handler: usize) -> usize;
+    fn write(fd: c_int, buf: *const u8, count: usize) -> isize;
+}
+
+extern "C" fn on_signal(_signum: c_int) {
+    let fd = PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        unsafe { write(fd, [1u8].as_ptr(), 1) };
+    }
+}
+
+// The write end is kept open for the life of the process so the handler
+// never touches a closed descriptor.
+pub fn self_pipe(signals: &[c_int]) -> io::Result<UnixStream> {
+    if PIPE.load(Ordering::Relaxed) >= 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "signal pipe already installed",
+        ));
+    }
+    let (rx, tx) = UnixStream::pair()?;
+    rx.set_nonblocking(true)?;
+    tx.set_nonblocking(true)?;
+    PIPE.store(tx.into_raw_fd(), Ordering::Relaxed);
+    for signum in signals {
+        unsafe { signal(*signum, on_signal as *const () as usize) };
+    }
+    Ok(rx)
+}
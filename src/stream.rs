@@ -1,54 +1,71 @@
 use std::{
-    io::{Error, Read, Write},
-    mem::ManuallyDrop,
+    borrow::Cow,
+    io::{self, Error, Read, Write},
     net::TcpStream,
+    os::{fd::AsRawFd, unix::net::UnixStream},
+    time::Duration,
 };
 
-use crate::frame::{Frame, Opcode};
+use crate::frame::{self, apply_mask, Frame, Opcode};
 
-pub struct WsStream {
+// A byte stream the reactor can poll. Anything that is Read + Write works
+// with WsStream and the blocking client; the reactor also needs a file
+// descriptor and a way to stop blocking.
+pub trait Transport: Read + Write + AsRawFd + Send {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Transport for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+pub struct WsStream<S: Read + Write = TcpStream> {
     frame: Frame,
-    pub stream: ManuallyDrop<TcpStream>,
-    buffer: Vec<u8>,
+    pub stream: S,
     cursor: usize,
 }
 
-impl WsStream {
-    pub fn new(stream: TcpStream) -> Self {
+impl<S: Read + Write> WsStream<S> {
+    pub fn new(stream: S) -> Self {
         Self {
             frame: Frame::new(true, Opcode::Text, None, 0),
-            stream: ManuallyDrop::new(stream),
+            stream,
             cursor: 0,
-            buffer: vec![],
         }
     }
 
     fn sext(&mut self, message: &str, isfinal: bool, mask: Option<[u8; 4]>) -> Result<(), Error> {
-        self.frame = Frame::new(
-            isfinal,
-            match isfinal {
-                true => Opcode::Text,
-                false => Opcode::Continuation,
-            },
-            mask,
-            message.len(),
-        );
-        self.write_frame()?;
-        self.write_all(message.as_bytes())
+        let opcode = match isfinal {
+            true => Opcode::Text,
+            false => Opcode::Continuation,
+        };
+        self.send(Frame::new(isfinal, opcode, mask, message.len()), message.as_bytes().into())
     }
 
+    // The message is owned, so a masked payload is masked in place.
     pub fn bin(&mut self, message: Vec<u8>, isfinal: bool, mask: Option<[u8; 4]>) -> Result<(), Error> {
-        self.frame = Frame::new(
-            isfinal,
-            match isfinal {
-                true => Opcode::Text,
-                false => Opcode::Continuation,
-            },
-            mask,
-            message.len(),
-        );
-        self.write_frame()?;
-        self.write_all(&message)
+        let opcode = match isfinal {
+            true => Opcode::Binary,
+            false => Opcode::Continuation,
+        };
+        self.send(Frame::new(isfinal, opcode, mask, message.len()), message.into())
     }
 
     pub fn text_fragment(&mut self, message: &str, mask: Option<[u8; 4]>) -> Result<(), Error> {
@@ -65,83 +82,75 @@ impl WsStream {
     }
 
     pub fn close(&mut self, mask: Option<[u8; 4]>, status: u16) -> Result<(), Error> {
-        self. frame = Frame::new(true, Opcode::Close, mask, 2);
-        self.write_frame()?;
-        self.write_all(&status.to_be_bytes())
+        self.send(Frame::new(true, Opcode::Close, mask, 2), status.to_be_bytes().to_vec().into())
     }
 
     pub fn bye(&mut self, mask: Option<[u8; 4]>) -> Result<(), Error> {
         let mut buf: Vec<u8> = vec![0, 0];
-        self.read_exact(&mut buf).expect("failed to read to end");
-        self.frame = Frame::new(true, Opcode::Close, mask, 2);
-        self.write_frame()?;
-        self.write_all(&buf)
+        self.read_exact(&mut buf)?;
+        self.send(Frame::new(true, Opcode::Close, mask, 2), buf.into())
     }
 
     pub fn ping(&mut self, message: &str, mask: Option<[u8; 4]>) ->Result<(), Error>  {
-        self.frame = Frame::new(true, Opcode::Ping, mask, message.len());
-        self.write_frame()?;
-        self.write_all(message.as_bytes())
+        self.send(Frame::new(true, Opcode::Ping, mask, message.len()), message.as_bytes().into())
     }
 
     pub fn pong(&mut self, mask: Option<[u8; 4]>) -> Result<(), Error> {
         let mut buf: Vec<u8> = vec![];
-        let n = self.read_to_end(&mut buf).expect("failed to read to end");
-        self.frame = Frame::new(true, Opcode::Pong, mask, n);
-        self.write_frame()?;
-        self.write_all(&buf)
+        let n = self.read_to_end(&mut buf)?;
+        self.send(Frame::new(true, Opcode::Pong, mask, n), buf.into())
     }
 
-    pub fn peer_addr(&self) -> Result<std::net::SocketAddr, Error> {
-        self.stream.peer_addr()
+    pub fn into_inner(self) -> S {
+        self.stream
     }
 
-    pub fn read_frame(&mut self) {
-        self.frame = Frame::read_frame(&self.stream);
+    pub fn read_frame(&mut self) -> Result<(), Error> {
+        self.frame = Frame::read_frame(&mut self.stream)?;
+        Ok(())
     }
 
     pub fn opcode(&self)-> Opcode {
         self.frame.opcode
     }
 
-    fn write_frame(&mut self) -> Result<(), Error> {
-        let blob = self.frame.to_blob();
-        self.write_all(&blob)
+    // Sends a whole frame: a masked payload is masked in place, copying a
+    // borrowed one first, and header and payload go out in one vectored
+    // write. The frame being read is left alone.
+    fn send(&mut self, frame: Frame, mut payload: Cow<'_, [u8]>) -> Result<(), Error> {
+        if let Some(mask) = frame.mask {
+            apply_mask(payload.to_mut(), mask, 0);
+        }
+        frame::write_frame(&mut self.stream, &frame, &payload)
+    }
+}
+
+impl WsStream<TcpStream> {
+    pub fn peer_addr(&self) -> Result<std::net::SocketAddr, Error> {
+        self.stream.peer_addr()
     }
 }
 
-impl Read for WsStream {
+// Reads the payload of the current frame and nothing past it, so the next
+// header stays in the stream.
+impl<S: Read + Write> Read for WsStream<S> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.cursor == self.frame.payload_length {
+        let remaining = self.frame.payload_length - self.cursor;
+        if remaining == 0 {
             self.cursor = 0;
             return Ok(0);
         }
-        let n = self.stream.read(buf)?;
-        match self.frame.mask {
-            Some(mask) => {
-                for c in 0..n {
-                    buf[c] = buf[c] ^ mask[self.cursor % 4];
-                    self.cursor += 1;
-                }
-            }
-            None => {}
+        let len = buf.len().min(remaining);
+        let n = self.stream.read(&mut buf[..len])?;
+        if let Some(mask) = self.frame.mask {
+            apply_mask(&mut buf[..n], mask, self.cursor);
         }
+        self.cursor += n;
         Ok(n)
     }
-
-    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
-        let mut cursor = 0;
-        loop {
-            let n = self.read(&mut buf[cursor..])?;
-            cursor += n;
-            if n == 0 {
-                break Ok(cursor);
-            }
-        }
-    }
 }
 
-impl Write for WsStream {
+impl<S: Read + Write> Write for WsStream<S> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         //if self.cursor + buf.len() > self.frame.payload_length {
         //    return Err(Error::new(
@@ -149,32 +158,87 @@ impl Write for WsStream {
         //        "too long input",
         //    ));
         //}
-        match self.frame.mask {
+        let n = match self.frame.mask {
+            // Masks through a stack buffer rather than copying the whole
+            // payload aside; a short write is reported like any other.
             Some(mask) => {
-                let _ = buf
-                    .bytes()
-                    .map(|b| self.buffer.push(b.unwrap() ^ mask[self.cursor % 4]));
-                self.flush().unwrap()
-            }
-            None => {
-                self.stream
-                    .write_all(buf)
-                    .expect("failed to write to stream");
+                let mut chunk = [0u8; 4096];
+                let chunk = &mut chunk[..buf.len().min(4096)];
+                chunk.copy_from_slice(&buf[..chunk.len()]);
+                apply_mask(chunk, mask, self.cursor);
+                self.stream.write(chunk)?
             }
-        }
-        self.cursor += buf.len();
+            None => self.stream.write(buf)?,
+        };
+        self.cursor += n;
 
         if self.cursor == self.frame.payload_length {
             self.cursor = 0;
             self.frame.payload_length = 0;
         }
 
-        Ok(buf.len())
+        Ok(n)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        let result = self.stream.write_all(&self.buffer);
-        self.buffer.clear();
-        result
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use super::WsStream;
+    use crate::{
+        frame::{apply_mask, Frame, Opcode},
+        message::Message,
+    };
+
+    fn decode(blob: &[u8]) -> (Frame, Vec<u8>, usize) {
+        let (frame, header) = Frame::parse(blob).unwrap().unwrap();
+        let end = header + frame.payload_length;
+        let mut payload = blob[header..end].to_vec();
+        if let Some(mask) = frame.mask {
+            apply_mask(&mut payload, mask, 0);
+        }
+        (frame, payload, end)
+    }
+
+    #[test]
+    fn masked_and_plain_frames() {
+        let text = "masked ".repeat(1000);
+        let mut stream = WsStream::new(Cursor::new(Vec::new()));
+        stream.text(&text, Some([1, 2, 3, 4])).unwrap();
+        stream.binary(vec![7; 300], Some([5, 6, 7, 8])).unwrap();
+        stream.ping("plain", None).unwrap();
+        let blob = stream.into_inner().into_inner();
+
+        let (frame, payload, used) = decode(&blob);
+        assert_eq!(frame.mask, Some([1, 2, 3, 4]));
+        assert_eq!(Message::from_parts(frame.opcode, payload), Some(Message::text(&text)));
+        let (frame, payload, next) = decode(&blob[used..]);
+        assert_eq!((frame.opcode, frame.mask), (Opcode::Binary, Some([5, 6, 7, 8])));
+        assert_eq!(payload, [7; 300]);
+        let (frame, payload, _) = decode(&blob[used + next..]);
+        assert_eq!((frame.opcode, frame.mask), (Opcode::Ping, None));
+        assert_eq!(payload, b"plain");
+    }
+
+    #[test]
+    fn reads_stop_at_the_frame() {
+        let mut blob = Message::text("hello").encode(Some([1, 2, 3, 4]));
+        blob.extend_from_slice(&Message::Ping(b"next".to_vec()).encode(None));
+        let mut stream = WsStream::new(Cursor::new(blob));
+
+        stream.read_frame().unwrap();
+        let mut payload = vec![];
+        stream.read_to_end(&mut payload).unwrap();
+        assert_eq!(payload, b"hello");
+        stream.read_frame().unwrap();
+        assert_eq!(stream.opcode(), Opcode::Ping);
+        let mut payload = [0u8; 16];
+        assert_eq!(stream.read(&mut payload).unwrap(), 4);
+        assert_eq!(&payload[..4], b"next");
     }
 }
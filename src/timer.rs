@@ -0,0 +1,230 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+const SLOTS: u64 = 256;
+// Deadlines past this tick are treated as this tick, which is centuries
+// away; it leaves room for the cursor arithmetic not to wrap.
+const MAX_TICK: u64 = u64::MAX / 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expired {
+    pub id: TimerId,
+    pub token: u64,
+}
+
+struct Entry {
+    id: TimerId,
+    tick: u64,
+    interval: Option<u64>,
+    token: u64,
+}
+
+// A hashed timing wheel: entries live in slot `tick % SLOTS` and only fire
+// once the cursor reaches their absolute tick, so long timers just sit out
+// the extra rotations.
+pub struct Timers {
+    origin: Instant,
+    resolution: Duration,
+    cursor: u64,
+    slots: Vec<Vec<Entry>>,
+    ticks: HashMap<TimerId, u64>,
+    next_id: u64,
+}
+
+impl Timers {
+    pub fn new(resolution: Duration) -> Self {
+        Self {
+            origin: Instant::now(),
+            resolution: resolution.max(Duration::from_millis(1)),
+            cursor: 0,
+            slots: (0..SLOTS).map(|_| vec![]).collect(),
+            ticks: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ticks.is_empty()
+    }
+
+    fn tick_of(&self, at: Instant) -> u64 {
+        let elapsed = at.saturating_duration_since(self.origin).as_nanos();
+        (elapsed / self.resolution.as_nanos()) as u64
+    }
+
+    fn ticks_in(&self, duration: Duration) -> u64 {
+        let nanos = self.resolution.as_nanos();
+        let ticks = duration.as_nanos().div_ceil(nanos);
+        u64::try_from(ticks)
+            .map_or(MAX_TICK, |ticks| ticks.min(MAX_TICK))
+            .max(1)
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        self.ticks.insert(entry.id, entry.tick);
+        self.slots[(entry.tick % SLOTS) as usize].push(entry);
+    }
+
+    pub fn schedule(&mut self, now: Instant, after: Duration, token: u64) -> TimerId {
+        self.add(now, after, None, token)
+    }
+
+    pub fn schedule_repeating(&mut self, now: Instant, every: Duration, token: u64) -> TimerId {
+        let interval = self.ticks_in(every);
+        self.add(now, every, Some(interval), token)
+    }
+
+    fn add(&mut self, now: Instant, after: Duration, interval: Option<u64>, token: u64) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        // Round the deadline up so a timer never fires before `after` elapses.
+        let tick = match now
+            .saturating_duration_since(self.origin)
+            .checked_add(after)
+        {
+            Some(elapsed) => {
+                let ticks = elapsed.as_nanos().div_ceil(self.resolution.as_nanos());
+                u64::try_from(ticks).map_or(MAX_TICK, |ticks| ticks.min(MAX_TICK))
+            }
+            None => MAX_TICK,
+        };
+        let tick = tick.max(self.cursor);
+        self.insert(Entry {
+            id,
+            tick,
+            interval,
+            token,
+        });
+        id
+    }
+
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        match self.ticks.remove(&id) {
+            Some(tick) => {
+                self.slots[(tick % SLOTS) as usize].retain(|entry| entry.id != id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.ticks.is_empty() {
+            return None;
+        }
+        let tick = (self.cursor..self.cursor + SLOTS)
+            .find(|tick| {
+                self.slots[(tick % SLOTS) as usize]
+                    .iter()
+                    .any(|entry| entry.tick == *tick)
+            })
+            .or_else(|| self.ticks.values().min().copied())?;
+        let nanos = self.resolution.as_nanos() as u64;
+        self.origin
+            .checked_add(Duration::from_nanos(nanos.saturating_mul(tick)))
+    }
+
+    pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        self.next_deadline()
+            .map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    pub fn expire(&mut self, now: Instant) -> Vec<Expired> {
+        let now_tick = self.tick_of(now);
+        let mut expired = vec![];
+        let mut rearm = vec![];
+        let end = now_tick.min(self.cursor + SLOTS - 1);
+        while self.cursor <= end {
+            let slot = &mut self.slots[(self.cursor % SLOTS) as usize];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].tick <= now_tick {
+                    let entry = slot.swap_remove(i);
+                    self.ticks.remove(&entry.id);
+                    expired.push(Expired {
+                        id: entry.id,
+                        token: entry.token,
+                    });
+                    if let Some(interval) = entry.interval {
+                        rearm.push(Entry {
+                            tick: now_tick.saturating_add(interval).min(MAX_TICK),
+                            ..entry
+                        });
+                    }
+                } else {
+                    i += 1;
+                }
+            }
+            self.cursor += 1;
+        }
+        self.cursor = self.cursor.max(now_tick + 1);
+        for entry in rearm {
+            self.insert(entry);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Timers;
+
+    #[test]
+    fn one_shot_repeating_and_cancel() {
+        let ms = Duration::from_millis;
+        let mut timers = Timers::new(ms(10));
+        let now = Instant::now();
+        timers.schedule(now, ms(50), 1);
+        let every = timers.schedule_repeating(now, ms(30), 2);
+        let cancelled = timers.schedule(now, ms(40), 3);
+        let far = timers.schedule(now, ms(10_000), 4);
+        assert!(timers.cancel(cancelled));
+        assert!(!timers.cancel(cancelled));
+        assert!(timers.next_timeout(now).unwrap() <= ms(40));
+
+        assert!(timers.expire(now + ms(20)).is_empty());
+        let fired: Vec<u64> = timers
+            .expire(now + ms(45))
+            .iter()
+            .map(|e| e.token)
+            .collect();
+        assert_eq!(fired, vec![2]);
+        let fired: Vec<u64> = timers
+            .expire(now + ms(80))
+            .iter()
+            .map(|e| e.token)
+            .collect();
+        assert!(fired.contains(&1) && fired.contains(&2));
+
+        assert!(timers.cancel(every));
+        assert_eq!(timers.len(), 1);
+        assert!(timers.expire(now + ms(5_000)).is_empty());
+        let fired = timers.expire(now + ms(10_020));
+        assert_eq!(fired[0].id, far);
+        assert!(timers.is_empty());
+    }
+
+    #[test]
+    fn huge_durations_never_fire() {
+        let mut timers = Timers::new(Duration::from_millis(10));
+        let now = Instant::now();
+        timers.schedule(now, Duration::MAX, 1);
+        timers.schedule_repeating(now, Duration::MAX, 2);
+        timers.schedule(now, Duration::from_secs(u64::MAX / 4), 3);
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert!(timers.next_timeout(now).is_none_or(|timeout| timeout > day));
+        assert!(timers.expire(now + day).is_empty());
+        assert_eq!(timers.len(), 3);
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    error::Error,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    auth::{percent_decode, percent_encode},
+    base64,
+    hmac::{constant_time_eq, hmac_sha256},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Claims {
+    pub subject: String,
+    // Seconds since the Unix epoch.
+    pub expires: u64,
+    pub extra: Vec<(String, String)>,
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+impl Claims {
+    pub fn new(subject: &str, ttl: Duration) -> Self {
+        Self {
+            subject: subject.to_string(),
+            expires: unix_time(SystemTime::now() + ttl),
+            extra: vec![],
+        }
+    }
+
+    pub fn with(mut self, name: &str, value: &str) -> Self {
+        self.extra.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.extra
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        unix_time(now) >= self.expires
+    }
+
+    fn encode(&self) -> String {
+        let mut fields = vec![
+            format!("sub={}", percent_encode(&self.subject)),
+            format!("exp={}", self.expires),
+        ];
+        for (name, value) in &self.extra {
+            fields.push(format!(
+                "{}={}",
+                percent_encode(name),
+                percent_encode(value)
+            ));
+        }
+        fields.join("&")
+    }
+
+    fn decode(payload: &str) -> Option<Self> {
+        let mut subject = None;
+        let mut expires = None;
+        let mut extra = vec![];
+        for field in payload.split('&') {
+            let (name, value) = field.split_once('=')?;
+            let (name, value) = (percent_decode(name)?, percent_decode(value)?);
+            match name.as_str() {
+                "sub" => subject = Some(value),
+                "exp" => expires = Some(value.parse().ok()?),
+                _ => extra.push((name, value)),
+            }
+        }
+        Some(Self {
+            subject: subject?,
+            expires: expires?,
+            extra,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::Malformed => "malformed token",
+            Self::BadSignature => "token signature mismatch",
+            Self::Expired => "token expired",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl Error for TokenError {}
+
+// Issues and checks compact tokens: base64url(claims) "." base64url(mac),
+// where the mac is HMAC-SHA256 over the encoded claims with the shared
+// secret. The claims are form-encoded: sub, exp, then any extra pairs.
+#[derive(Clone)]
+pub struct Signer {
+    secret: Vec<u8>,
+}
+
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signer").finish_non_exhaustive()
+    }
+}
+
+impl Signer {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            secret: secret.to_vec(),
+        }
+    }
+
+    pub fn sign(&self, claims: &Claims) -> String {
+        let payload = base64::encode_url(claims.encode().as_bytes());
+        let mac = hmac_sha256(&self.secret, payload.as_bytes());
+        format!("{payload}.{}", base64::encode_url(&mac))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        self.verify_at(token, SystemTime::now())
+    }
+
+    pub fn verify_at(&self, token: &str, now: SystemTime) -> Result<Claims, TokenError> {
+        let (payload, mac) = token.split_once('.').ok_or(TokenError::Malformed)?;
+        let expected = hmac_sha256(&self.secret, payload.as_bytes());
+        let mac = base64::decode_url(mac).map_err(|_| TokenError::Malformed)?;
+        if !constant_time_eq(&mac, &expected) {
+            return Err(TokenError::BadSignature);
+        }
+        let claims = base64::decode_url(payload)
+            .ok()
+            .and_then(|payload| String::from_utf8(payload).ok())
+            .and_then(|payload| Claims::decode(&payload))
+            .ok_or(TokenError::Malformed)?;
+        if claims.is_expired(now) {
+            return Err(TokenError::Expired);
+        }
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{Claims, Signer, TokenError};
+
+    #[test]
+    fn sign_and_verify() {
+        let signer = Signer::new(b"shared secret");
+        let claims = Claims::new("ann@example.com", Duration::from_secs(60)).with("room", "a&b");
+        let token = signer.sign(&claims);
+        assert!(!token.contains(['+', '/', '=']));
+        assert_eq!(signer.verify(&token), Ok(claims.clone()));
+        assert_eq!(signer.verify(&token).unwrap().get("room"), Some("a&b"));
+
+        let later = SystemTime::now() + Duration::from_secs(120);
+        assert_eq!(signer.verify_at(&token, later), Err(TokenError::Expired));
+        assert_eq!(
+            Signer::new(b"other").verify(&token),
+            Err(TokenError::BadSignature)
+        );
+
+        let (payload, mac) = token.split_once('.').unwrap();
+        let forged = Claims {
+            subject: "admin".to_string(),
+            ..claims
+        };
+        let forged = format!("{}.{mac}", signer.sign(&forged).split_once('.').unwrap().0);
+        assert_eq!(signer.verify(&forged), Err(TokenError::BadSignature));
+        assert_eq!(signer.verify(payload), Err(TokenError::Malformed));
+    }
+}